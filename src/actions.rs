@@ -1,12 +1,26 @@
 use std::error::Error;
-use std::mem::take;
-use std::time::Duration;
-use std::net::IpAddr;
+use std::mem::{replace, take};
+use std::time::{Duration, Instant};
+use std::net::SocketAddr;
 use std::cmp::min;
 use crossterm::event::{Event, KeyCode, KeyModifiers, read, poll};
 use clipboard::{ClipboardProvider, ClipboardContext};
-use crate::data::{App, InputMode, now_fmt, Message, MessageType, set_status, Peer, LogState};
+use crate::data::{App, InputMode, now_fmt, Message, MessageType, set_status, Peer, LogState,
+                  MessageRequest, Transfer, TransferState, Outbound, Revision, EVERYONE};
 use crate::network::{ToNet, message_to_net, message_from_net, FromNet};
+use crate::commands::run_command;
+use crate::known_peers::{check_pin, save_known_peers, is_verified, find_flag, Pin};
+use crate::identity::short_fingerprint;
+use crate::ping::Presence;
+use crate::groups::{Group, save_groups};
+
+/// How often peers are told again that the user is still typing.
+const TYPING_RESEND: Duration = Duration::from_secs(3);
+/// How long a peer is shown as typing after it last said so. Longer than `TYPING_RESEND` so that
+/// it doesn't flicker.
+const TYPING_SHOWN: Duration = Duration::from_secs(6);
+/// How often queued messages are tried again for peers that are still in the list.
+const QUEUE_RETRY: Duration = Duration::from_secs(30);
 
 pub fn input_async(app: &mut App) {
     while let Some(message) = message_from_net(app) {
@@ -17,74 +31,246 @@ pub fn input_async(app: &mut App) {
                 show_error(app, content);
             }
             FromNet::ShowLocalName(name) => app.lan.local_name = name,
-            FromNet::ShowLocalAddress(addr) => app.lan.local_addr = addr,
-            FromNet::Peer {name, address} => {
+            FromNet::ShowLocalIdentity(identity) => app.lan.local_identity = identity,
+            FromNet::ShowLocalAddresses {ipv6, addresses} => {
+                app.lan.local_addrs.retain(|a| a.is_ipv6() != ipv6);
+                app.lan.local_addrs.extend(addresses);
+                app.lan.local_addrs.sort_by_key(|a| a.is_ipv6());
+            }
+            FromNet::Peer {name, address, identity, hostname, rooms, presence, note} => {
+                if find_flag(&app.known_peers, identity.as_deref(), address, |a| a.blocked) {
+                    remove_peer(app, address);
+                    continue;
+                }
+
+                // Pings repeat every few seconds so only check when something changes.
+                let found = app.lan.peers.iter().find(|a| a.address == address);
+                let (changed, mismatch) = match found {
+                    Some(peer) if peer.name == name && peer.identity == identity => {
+                        (false, peer.mismatch)
+                    }
+                    _ => (true, check_identity(app, &name, address, identity.as_deref())),
+                };
+                let verified = !mismatch && identity.as_ref()
+                    .is_some_and(|a| is_verified(&app.known_peers, a, &name));
+
                 if let Some(peer) = app.lan.peers.iter_mut().find(|a| a.address == address) {
                     peer.name.clear();
                     peer.name.push_str(&name);
+                    peer.identity = identity;
+                    peer.mismatch = mismatch;
+                    peer.verified = verified;
+                    peer.hostname = hostname;
+                    peer.rooms = rooms;
+                    peer.presence = presence;
+                    peer.note = note;
                 } else {
                     let peer = Peer {
                         name: name.clone(),
                         address,
+                        identity,
+                        mismatch,
+                        verified,
+                        hostname,
+                        rooms,
+                        presence,
+                        note,
                     };
 
-                    if !app.recipient.valid && app.recipient.peer.name == name
-                    && app.recipient.peer.address == address {
+                    let picked = app.recipient.everyone || app.recipient.group.is_some();
+                    if !app.recipient.valid && !picked && is_recipient(app, &peer) {
                         let index = min(app.lan.peers.len(), app.recipient.index);
                         app.recipient.index = index;
                         app.recipient.valid = true;
+                        app.recipient.peer = peer.clone();
 
                         app.lan.peers.insert(index, peer);
                     } else {
                         app.lan.peers.push(peer);
                     }
                 }
+                if changed && !mismatch {
+                    send_queued(app, address);
+                }
+            }
+            FromNet::Peerbgone(address) => remove_peer(app, address),
+            FromNet::Edited {source, id, content} => {
+                let found = app.messages.iter_mut().find(|a| a.origin == Some((source, id)));
+                if let Some(message) = found {
+                    revise(message, content);
+                }
+            }
+            FromNet::Typing(address) => {
+                app.typing.retain(|a| a.0 != address);
+                app.typing.push((address, Instant::now()));
             }
-            FromNet::Peerbgone(address) => {
-                let found = app.lan.peers
-                    .iter().position(|r| r.address == address);
-                if let Some(index) = found {
-                    if app.recipient.valid && app.recipient.index == index {
-                        app.recipient.valid = false;
+            FromNet::Request {address, count} => {
+                let found = app.requests.iter().position(|a| a.address == address);
+                match found {
+                    _ if count == 0 => app.requests.retain(|a| a.address != address),
+                    Some(index) => app.requests[index].count = count,
+                    None => {
+                        app.requests.push(MessageRequest {address, count});
+                        let name = peer_name(app, address);
+                        show_note(app, format!(
+                            "{} wants to send you messages. Type /accept {} to see them, \
+                            /always {} to always accept them from this computer or /reject {}.",
+                            name, name, name, name));
                     }
-
-                    app.lan.peers.remove(index);
                 }
             }
             FromNet::SendFailed(message_id) => {
-                update_message(app, message_id, MessageType::SendFailed);
+                let found = app.outbox.iter_mut().find(|a| a.message_id == message_id);
+                if let Some(outbound) = found {
+                    outbound.queued = true;
+                    update_message(app, message_id, MessageType::Queued);
+                } else {
+                    update_message(app, message_id, MessageType::SendFailed);
+                }
             }
             FromNet::SendArrived(message_id) => {
+                app.outbox.retain(|a| a.message_id != message_id);
                 update_message(app, message_id, MessageType::Sent);
             }
-            FromNet::ShowMessage {source, content} => show_message(app, source, content),
+            FromNet::SendDelivered(message_id) => {
+                // Receipts can pass each other on the way.
+                let read = app.messages.iter()
+                    .any(|a| a.message_id == message_id && a.direction == MessageType::Read);
+                if !read {
+                    update_message(app, message_id, MessageType::Delivered);
+                }
+            }
+            FromNet::SendRead(message_id) => {
+                update_message(app, message_id, MessageType::Read);
+            }
+            FromNet::BroadcastSent {message_id, sent, failed} => {
+                let direction = if sent > 0 { MessageType::Sent } else { MessageType::SendFailed };
+                update_message(app, message_id, direction);
+                let found = app.messages.iter_mut().find(|a| a.message_id == message_id);
+                // Keeps the label that it was sent with, for everyone or for a group.
+                if let Some(message) = found {
+                    message.name = format!("{} {} sent, {} failed", message.name, sent, failed);
+                }
+            }
+            FromNet::ShowMessage {source, content, receipt, group} => {
+                show_message(app, source, content, receipt, group);
+            }
+            FromNet::GroupShared {source, group} => on_group(app, source, group),
             FromNet::LogStarted(path) => {
                 if app.logging != LogState::Active {
-                    let timestamp = now_fmt(app);
-                    app.messages.push(Message {
-                        timestamp,
-                        direction: MessageType::Note,
-                        name: "".into(),
-                        content: format!("Logging to {}", path),
-                        message_id: 0,
-                    });
+                    show_note(app, format!("Logging to {}", path));
                     app.logging = LogState::Active;
                 }
             }
             FromNet::LogStopped => {
                 app.logging = LogState::Inactive;
             }
+            FromNet::ShowRejected(counts) => {
+                // Only shown when asked for, so there's nothing to redraw.
+                app.rejected = counts;
+                continue;
+            }
+            FromNet::FileOffer {id, source, name, size, folder} => {
+                let identity = app.lan.peers.iter()
+                    .find(|a| a.address == source)
+                    .and_then(|a| a.identity.clone());
+                if !find_flag(&app.known_peers, identity.as_deref(), source, |a| a.muted) {
+                    app.unread += 1;
+                }
+                let transfer = Transfer {
+                    id,
+                    address: source,
+                    name,
+                    size: Some(size),
+                    folder,
+                    outgoing: false,
+                    state: TransferState::Offered,
+                };
+                show_transfer(app, transfer);
+            }
+            FromNet::FileOffered {id, size} => {
+                if let Some(transfer) = app.transfers.iter_mut().find(|a| a.id == id) {
+                    transfer.size = Some(size);
+                }
+                update_transfer(app, id, TransferState::Offered);
+            }
+            FromNet::FileProgress {id, done} => {
+                update_transfer(app, id, TransferState::Running(done));
+            }
+            FromNet::FileDone {id, path} => update_transfer(app, id, TransferState::Done(path)),
+            FromNet::FileFailed {id, error} => {
+                update_transfer(app, id, TransferState::Failed(error));
+            }
         }
         app.needs_redraw = true;
     }
 }
 
+/// Sends queued messages again every so often. Peers that stay in the list after a failed send
+/// never come back, so waiting for them like for the ones that left would keep the messages
+/// forever.
+pub fn retry_queued(app: &mut App) {
+    let since = *app.queue_tried.get_or_insert_with(Instant::now);
+    if since.elapsed() < QUEUE_RETRY {
+        return;
+    }
+    app.queue_tried = Some(Instant::now());
+    if !app.outbox.iter().any(|a| a.queued) {
+        return;
+    }
+    let addresses = app.lan.peers.iter()
+        .filter(|a| !a.mismatch)
+        .map(|a| a.address)
+        .collect::<Vec<_>>();
+    for address in addresses {
+        send_queued(app, address);
+    }
+}
+
+/// Stops showing peers as typing once they've gone quiet for long enough.
+pub fn expire_typing(app: &mut App) {
+    let count = app.typing.len();
+    app.typing.retain(|a| a.1.elapsed() < TYPING_SHOWN);
+    if app.typing.len() != count {
+        app.needs_redraw = true;
+    }
+}
+
+/// Tells the selected peer that the user is typing something for it, at most every few seconds.
+/// Not sent to everyone or to groups.
+fn send_typing(app: &mut App) {
+    if !app.recipient.valid || app.editing.is_some() || app.input.trim().is_empty() {
+        return;
+    }
+    let address = app.recipient.peer.address;
+    if let Some((last, since)) = app.typing_sent {
+        if last == address && since.elapsed() < TYPING_RESEND {
+            return;
+        }
+    }
+    app.typing_sent = Some((address, Instant::now()));
+    if message_to_net(app, ToNet::Typing(address)).is_err() {
+        show_error(app, "async thread not started".into());
+    }
+}
+
 pub fn input_terminal(app: &mut App, timeout: Duration) -> Result<(), Box<dyn Error>> {
     if !poll(timeout)? {
         return Ok(());
     }
     let key = match read()? {
-        Event::Key(key) => key,
+        Event::Key(key) => {
+            // There's no way to tell whether the terminal has focus, so a key press is the best
+            // sign that the user has seen what's there.
+            app.unread = 0;
+            app.last_input = Some(Instant::now());
+            send_read_receipts(app);
+            if app.auto_away {
+                let note = take(&mut app.note);
+                set_presence(app, Presence::Available, note);
+            }
+            key
+        }
         Event::Resize(_, _) => {
             app.needs_redraw = true;
             return Ok(());
@@ -100,6 +286,10 @@ pub fn input_terminal(app: &mut App, timeout: Duration) -> Result<(), Box<dyn Er
         (InputMode::Normal, KeyCode::Char('c'), KeyModifiers::ALT) => {
             copy(app)?;
         }
+        (InputMode::Command, KeyCode::Char('v'), KeyModifiers::ALT) => {
+            let mut ctx: ClipboardContext = ClipboardProvider::new()?;
+            app.command.push_str(&ctx.get_contents()?);
+        }
         (_, KeyCode::Char('v'), KeyModifiers::ALT) => {
             paste(app)?;
 
@@ -109,35 +299,13 @@ pub fn input_terminal(app: &mut App, timeout: Duration) -> Result<(), Box<dyn Er
         (_, KeyCode::Tab, KeyModifiers::SHIFT) => {
             // NOTE: Shift+Tab doesn't work on the Windows Command Prompt
             // https://stackoverflow.com/questions/6129143/how-to-map-shift-tab-in-vim-cygwin-windows-cmd-exe#6129580
-            if app.lan.peers.len() > 0 {
-                if !app.recipient.valid {
-                    app.recipient.index = app.lan.peers.len() - 1;
-                } else {
-                    if app.recipient.index == 0 {
-                        app.recipient.index = app.lan.peers.len();
-                    }
-                    app.recipient.index -= 1;
-                }
-                app.recipient.peer = app.lan.peers[app.recipient.index].clone();
-                app.recipient.valid = true;
-            }
+            move_recipient(app, false);
         }
         (_, KeyCode::Tab, KeyModifiers::NONE) => {
-            if app.lan.peers.len() > 0 {
-                if !app.recipient.valid {
-                    app.recipient.index = 0;
-                } else {
-                    app.recipient.index += 1;
-                    if app.recipient.index >= app.lan.peers.len() {
-                        app.recipient.index = 0;
-                    }
-                }
-                app.recipient.peer = app.lan.peers[app.recipient.index].clone();
-                app.recipient.valid = true;
-            }
+            move_recipient(app, true);
         }
         (InputMode::Normal, KeyCode::Enter, _) => {
-            if app.recipient.valid {
+            if app.recipient.is_chosen() {
                 app.input_mode = InputMode::Editing;
                 app.message_highlight = None; // TODO: this should be an InputMode
             }
@@ -145,6 +313,18 @@ pub fn input_terminal(app: &mut App, timeout: Duration) -> Result<(), Box<dyn Er
         (InputMode::Normal, KeyCode::Char('q'), _) => {
             app.quitting = true;
         }
+        (InputMode::Normal, KeyCode::Char('/'), _) => {
+            app.command.clear();
+            app.input_mode = InputMode::Command;
+        }
+        (InputMode::Normal, KeyCode::Char('e'), _) => {
+            start_editing(app);
+        }
+        (InputMode::Normal, KeyCode::Delete, _) => {
+            if let Some(message_id) = selected_changeable(app) {
+                change_sent(app, message_id, None);
+            }
+        }
         (InputMode::Normal, KeyCode::Esc, _) => {
             if app.message_highlight.is_some() {
                 app.message_highlight = None;
@@ -154,7 +334,7 @@ pub fn input_terminal(app: &mut App, timeout: Duration) -> Result<(), Box<dyn Er
         }
 
         (InputMode::Normal, KeyCode::Up, _) => {
-            if app.messages.len() > 0 {
+            if !app.messages.is_empty() {
                 match app.message_highlight {
                     None => app.message_highlight = Some(app.messages.len() as u16 - 1),
                     Some(0) => {}
@@ -163,7 +343,7 @@ pub fn input_terminal(app: &mut App, timeout: Duration) -> Result<(), Box<dyn Er
             }
         }
         (InputMode::Normal, KeyCode::Down, _) => {
-            if app.messages.len() > 0 {
+            if !app.messages.is_empty() {
                 match app.message_highlight {
                     None => app.message_highlight = Some(app.messages.len() as u16 - 1),
                     Some(old) => {
@@ -179,9 +359,18 @@ pub fn input_terminal(app: &mut App, timeout: Duration) -> Result<(), Box<dyn Er
             app.input.push('\n');
         }
         (InputMode::Editing, KeyCode::Enter, _) => {
-            if !app.recipient.valid {
+            if let Some((message_id, draft)) = app.editing.take() {
+                let content = replace(&mut app.input, draft);
+                let unchanged = app.messages.iter()
+                    .any(|a| a.message_id == message_id && a.content == content);
+                // Taking everything out doesn't retract it. That's what [Del] is for.
+                if !content.trim().is_empty() && !unchanged {
+                    change_sent(app, message_id, Some(content));
+                }
+                app.input_mode = InputMode::Normal;
+            } else if !app.recipient.is_chosen() {
                 app.input_mode = InputMode::Normal;
-            } else if app.input.trim().len() > 0 {
+            } else if !app.input.trim().is_empty() {
                 let content = take(&mut app.input);
                 send(app, content);
             } else {
@@ -192,13 +381,38 @@ pub fn input_terminal(app: &mut App, timeout: Duration) -> Result<(), Box<dyn Er
         (InputMode::Editing, KeyCode::Char(c), k) => {
             if !k.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) {
                 app.input.push(c);
+                send_typing(app);
             }
         }
         (InputMode::Editing, KeyCode::Backspace, _) => {
             app.input.pop();
+            send_typing(app);
         }
         (InputMode::Editing, KeyCode::Esc, _) => {
+            if let Some((_, draft)) = app.editing.take() {
+                app.input = draft;
+            }
+            app.input_mode = InputMode::Normal;
+        }
+
+        (InputMode::Command, KeyCode::Enter, _) => {
+            app.input_mode = InputMode::Normal;
+            let command = take(&mut app.command);
+            run_command(app, &command);
+        }
+        (InputMode::Command, KeyCode::Char(c), k) => {
+            if !k.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) {
+                app.command.push(c);
+            }
+        }
+        (InputMode::Command, KeyCode::Backspace, _) => {
+            if app.command.pop().is_none() {
+                app.input_mode = InputMode::Normal;
+            }
+        }
+        (InputMode::Command, KeyCode::Esc, _) => {
             app.input_mode = InputMode::Normal;
+            app.pairing = None;
         }
         _ => {
             return Ok(());
@@ -209,7 +423,70 @@ pub fn input_terminal(app: &mut App, timeout: Duration) -> Result<(), Box<dyn Er
     Ok(())
 }
 
-fn update_message(app: &mut App, message_id: u32, new_type: MessageType) {
+/// Checks the peers with this name or one of these identities against the pins again after the
+/// pins change.
+pub fn recheck_identities(app: &mut App, name: &str, identities: &[String]) {
+    for index in 0..app.lan.peers.len() {
+        let peer = app.lan.peers[index].clone();
+        let affected = peer.name == name
+            || peer.identity.as_ref().is_some_and(|a| identities.contains(a));
+        if !affected {
+            continue;
+        }
+
+        let mismatch = check_identity(app, &peer.name, peer.address, peer.identity.as_deref());
+        let verified = !mismatch && peer.identity.as_ref()
+            .is_some_and(|a| is_verified(&app.known_peers, a, &peer.name));
+        app.lan.peers[index].mismatch = mismatch;
+        app.lan.peers[index].verified = verified;
+    }
+}
+
+/// Returns whether the identity conflicts with the pinned one.
+fn check_identity(app: &mut App, name: &str, address: SocketAddr, identity: Option<&str>)
+        -> bool {
+    let identity = if let Some(a) = identity {
+        a
+    } else {
+        // Otherwise leaving out the handshake would be a way to take a pinned name.
+        let pinned = app.known_peers.iter().any(|a| a.is_pin() && a.name == name);
+        if pinned {
+            show_error(app, format!(
+                "WARNING: {} at {} hasn't proven that it has the key that was first seen on {}. \
+                It may be a different computer pretending to be {}. Use /forget {} if sclan was \
+                replaced with an older version there.",
+                name, address, name, name, name));
+        }
+        return pinned;
+    };
+
+    match check_pin(&mut app.known_peers, identity, name) {
+        Pin::Same => false,
+        Pin::New => {
+            if let Err(error) = save_known_peers(&app.known_peers) {
+                show_error(app, format!("error: {}", error));
+            }
+            false
+        }
+        Pin::Renamed(old_name) => {
+            show_error(app, format!(
+                "WARNING: {} at {} has the key that was first seen on {}. If that computer \
+                wasn't renamed, it may be pretending. Use /forget {} to accept the new name.",
+                name, address, old_name, old_name));
+            true
+        }
+        Pin::Changed => {
+            show_error(app, format!(
+                "WARNING: {} at {} has a different key ({}) than the first time it was seen. It \
+                may be a different computer pretending to be {}. Use /forget {} if it was \
+                reinstalled.",
+                name, address, short_fingerprint(identity), name, name));
+            true
+        }
+    }
+}
+
+pub fn update_message(app: &mut App, message_id: u32, new_type: MessageType) {
     for message in &mut app.messages {
         if message.message_id == message_id {
             message.direction = new_type;
@@ -219,7 +496,7 @@ fn update_message(app: &mut App, message_id: u32, new_type: MessageType) {
     }
 }
 
-fn show_error(app: &mut App, content: String) {
+pub fn show_error(app: &mut App, content: String) {
     let timestamp = now_fmt(app);
 
     app.messages.push(Message {
@@ -228,15 +505,72 @@ fn show_error(app: &mut App, content: String) {
         name: "".into(),
         content,
         message_id: 0,
+        transfer: None,
+        receipt: None,
+        origin: None,
+        revision: Revision::Original,
+    });
+}
+
+pub fn show_note(app: &mut App, content: String) {
+    let timestamp = now_fmt(app);
+
+    app.messages.push(Message {
+        timestamp,
+        direction: MessageType::Note,
+        name: "".into(),
+        content,
+        message_id: 0,
+        transfer: None,
+        receipt: None,
+        origin: None,
+        revision: Revision::Original,
     });
 }
 
-fn show_message(app: &mut App, address: IpAddr, content: String) {
-    let name = if let Some(peer) = app.lan.peers.iter_mut().find(|a| a.address == address) {
+pub fn remove_peer(app: &mut App, address: SocketAddr) {
+    app.typing.retain(|a| a.0 != address);
+    let found = app.lan.peers
+        .iter().position(|r| r.address == address);
+    if let Some(index) = found {
+        if app.recipient.valid && app.recipient.index == index {
+            app.recipient.valid = false;
+        }
+
+        app.lan.peers.remove(index);
+    }
+}
+
+/// The address stands in for peers that aren't in the list.
+pub fn peer_name(app: &App, address: SocketAddr) -> String {
+    if let Some(peer) = app.lan.peers.iter().find(|a| a.address == address) {
         peer.name.clone()
     } else {
         address.to_string()
-    };
+    }
+}
+
+fn show_message(app: &mut App, address: SocketAddr, content: String, receipt: Option<u64>,
+        group: Option<Group>) {
+    let identity = app.lan.peers.iter()
+        .find(|a| a.address == address)
+        .and_then(|a| a.identity.clone());
+    // The network thread drops them too but some may have been on the way when it was blocked.
+    if find_flag(&app.known_peers, identity.as_deref(), address, |a| a.blocked) {
+        return;
+    }
+    if !find_flag(&app.known_peers, identity.as_deref(), address, |a| a.muted) {
+        app.unread += 1;
+    }
+    // Whatever it was typing is here now.
+    app.typing.retain(|a| a.0 != address);
+
+    let mut name = peer_name(app, address);
+    // A group that doesn't check out is shown as a message to just this computer.
+    if let Some(group) = group.filter(|a| is_group_from(app, a, address, false)) {
+        name = format!("{} in [{}]", name, group.name);
+        store_group(app, group);
+    }
 
     let timestamp = now_fmt(app);
 
@@ -248,7 +582,225 @@ fn show_message(app: &mut App, address: IpAddr, content: String) {
         // TODO: maybe also save source address so they can RE-name with the peer
         content,
         message_id: 0,
+        transfer: None,
+        receipt: receipt.filter(|_| app.config.read_receipts).map(|a| (address, a)),
+        origin: receipt.map(|a| (address, a)),
+        revision: Revision::Original,
+    });
+
+    if let Some(id) = receipt {
+        if message_to_net(app, ToNet::Receipt {address, id, read: false}).is_err() {
+            show_error(app, "async thread not started".into());
+        }
+    }
+}
+
+/// Whether a group from a peer can be taken: the peer has to be in it, or in the copy that's
+/// already here if it's taking this computer out of it. Also checks that this computer is in it
+/// unless it's being taken out.
+fn is_group_from(app: &App, group: &Group, address: SocketAddr, removing: bool) -> bool {
+    let found = app.lan.peers.iter().find(|a| a.address == address);
+    let sender = match found.and_then(|a| a.identity.as_ref()) {
+        Some(a) => a,
+        None => return false,
+    };
+    let known = app.groups.iter().find(|a| a.id == group.id);
+    let member = group.members.contains(sender)
+        || known.is_some_and(|a| a.members.contains(sender));
+    let included = group.members.contains(&app.lan.local_identity);
+    group.is_valid() && member && included != removing
+}
+
+/// Adds the group, or replaces the copy of it that's here, and saves the groups.
+pub fn store_group(app: &mut App, group: Group) {
+    match app.groups.iter().position(|a| a.id == group.id) {
+        Some(index) => app.groups[index] = group,
+        None => app.groups.push(group),
+    }
+    if let Err(error) = save_groups(&app.groups) {
+        show_error(app, format!("error: {}", error));
+    }
+}
+
+/// Forgets the group and saves the groups.
+pub fn remove_group(app: &mut App, id: u64) {
+    app.groups.retain(|a| a.id != id);
+    if app.recipient.group == Some(id) {
+        app.recipient.group = None;
+    }
+    if let Err(error) = save_groups(&app.groups) {
+        show_error(app, format!("error: {}", error));
+    }
+}
+
+fn on_group(app: &mut App, address: SocketAddr, group: Group) {
+    let name = peer_name(app, address);
+    let known = app.groups.iter().any(|a| a.id == group.id);
+    if is_group_from(app, &group, address, true) {
+        if known {
+            remove_group(app, group.id);
+            show_note(app, format!("{} took you out of [{}]", name, group.name));
+        }
+        return;
+    }
+    if !is_group_from(app, &group, address, false) {
+        return;
+    }
+    if !known {
+        show_note(app, format!("{} added you to [{}] - press [Tab] to pick it", name,
+            group.name));
+    }
+    store_group(app, group);
+}
+
+/// Tells the senders of the messages that haven't been seen yet that the user has seen them.
+fn send_read_receipts(app: &mut App) {
+    let receipts = app.messages.iter_mut()
+        .filter_map(|a| a.receipt.take())
+        .collect::<Vec<_>>();
+    for (address, id) in receipts {
+        if message_to_net(app, ToNet::Receipt {address, id, read: true}).is_err() {
+            show_error(app, "async thread not started".into());
+            return;
+        }
+    }
+}
+
+/// Adds the transfer along with a message to show it.
+pub fn show_transfer(app: &mut App, transfer: Transfer) {
+    let timestamp = now_fmt(app);
+    let name = peer_name(app, transfer.address);
+
+    app.messages.push(Message {
+        timestamp,
+        direction: transfer_direction(&transfer),
+        name,
+        content: transfer_text(&transfer),
+        message_id: 0,
+        transfer: Some(transfer.id),
+        receipt: None,
+        origin: None,
+        revision: Revision::Original,
     });
+    app.transfers.push(transfer);
+}
+
+/// Updates the transfer and its message. Finished transfers stay the way they ended, since
+/// progress can still be on the way after one is cancelled.
+pub fn update_transfer(app: &mut App, id: u64, state: TransferState) {
+    let found = app.transfers.iter_mut().find(|a| a.id == id);
+    let transfer = match found {
+        Some(a) if matches!(a.state, TransferState::Offered | TransferState::Running(_)) => a,
+        _ => return,
+    };
+    transfer.state = state;
+
+    for message in app.messages.iter_mut().filter(|a| a.transfer == Some(id)) {
+        message.direction = transfer_direction(transfer);
+        message.content = transfer_text(transfer);
+    }
+    app.needs_redraw = true;
+}
+
+fn transfer_direction(transfer: &Transfer) -> MessageType {
+    match transfer.state {
+        _ if !transfer.outgoing => MessageType::Received,
+        TransferState::Offered | TransferState::Running(_) => MessageType::Sending,
+        TransferState::Done(_) => MessageType::Sent,
+        TransferState::Failed(_) => MessageType::SendFailed,
+    }
+}
+
+fn transfer_text(transfer: &Transfer) -> String {
+    let state = match &transfer.state {
+        TransferState::Offered if transfer.outgoing => "waiting for it to be accepted".to_string(),
+        TransferState::Offered => "/receive to save it or /decline".to_string(),
+        TransferState::Running(done) => {
+            let size = transfer.size.unwrap_or(0);
+            format!("{}%", (done * 100).checked_div(size).unwrap_or(100))
+        }
+        TransferState::Done(Some(path)) => format!("saved as {} - checksum verified", path),
+        TransferState::Done(None) => "sent".to_string(),
+        TransferState::Failed(error) => format!("failed: {}", error),
+    };
+    let kind = if transfer.folder { "folder" } else { "file" };
+    match transfer.size {
+        Some(size) => format!("[{}] {} ({}) - {}", kind, transfer.name, size_label(size), state),
+        None => format!("[{}] {} - {}", kind, transfer.name, state),
+    }
+}
+
+fn size_label(size: u64) -> String {
+    const UNITS: &[&str] = &["KB", "MB", "GB", "TB"];
+    if size < 1024 {
+        return format!("{} bytes", size);
+    }
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// The id of the highlighted message if it's one that the user sent and can still change.
+fn selected_changeable(app: &App) -> Option<u32> {
+    app.message_highlight
+        .and_then(|i| app.messages.get(i as usize))
+        .filter(|a| a.is_changeable())
+        .map(|a| a.message_id)
+}
+
+/// Puts the highlighted message in the input to be edited, setting aside what was there.
+fn start_editing(app: &mut App) {
+    let found = app.message_highlight
+        .and_then(|i| app.messages.get(i as usize))
+        .filter(|a| a.is_changeable());
+    let (message_id, content) = if let Some(a) = found {
+        (a.message_id, a.content.clone())
+    } else {
+        return;
+    };
+    let draft = replace(&mut app.input, content);
+    app.editing = Some((message_id, draft));
+    app.input_mode = InputMode::Editing;
+    app.message_highlight = None;
+}
+
+/// Changes a sent message here and for the peers that got it. None retracts it.
+fn change_sent(app: &mut App, message_id: u32, content: Option<String>) {
+    let found = app.messages.iter_mut()
+        .find(|a| a.message_id == message_id && a.transfer.is_none());
+    if let Some(message) = found {
+        revise(message, content.clone());
+    }
+    // Queued ones go out as they are now when their peers come back.
+    match &content {
+        Some(content) => {
+            for outbound in app.outbox.iter_mut().filter(|a| a.message_id == message_id) {
+                outbound.content = content.clone();
+            }
+        }
+        None => app.outbox.retain(|a| a.message_id != message_id),
+    }
+    if message_to_net(app, ToNet::Edit {message_id, content}).is_err() {
+        show_error(app, "async thread not started".into());
+    }
+}
+
+/// None for a message that was retracted.
+fn revise(message: &mut Message, content: Option<String>) {
+    match content {
+        Some(content) => {
+            message.content = content;
+            message.revision = Revision::Edited;
+        }
+        None => {
+            message.content.clear();
+            message.revision = Revision::Retracted;
+        }
+    }
 }
 
 fn next_message_id(app: &mut App) -> u32 {
@@ -256,34 +808,199 @@ fn next_message_id(app: &mut App) -> u32 {
     app.last_message_id
 }
 
+/// Goes through the entry for everyone, then the groups, then the peers, in the order that
+/// they're listed.
+fn move_recipient(app: &mut App, forward: bool) {
+    if app.lan.peers.is_empty() && app.groups.is_empty() {
+        return;
+    }
+    let count = 1 + app.groups.len() + app.lan.peers.len();
+    let current = if app.recipient.everyone {
+        Some(0)
+    } else if let Some(id) = app.recipient.group {
+        app.groups.iter().position(|a| a.id == id).map(|a| 1 + a)
+    } else if app.recipient.valid {
+        Some(1 + app.groups.len() + app.recipient.index)
+    } else {
+        None
+    };
+    let next = match current {
+        Some(a) if forward => (a + 1) % count,
+        Some(a) => (a + count - 1) % count,
+        None if forward => 0,
+        None => count - 1,
+    };
+
+    app.recipient.everyone = next == 0;
+    app.recipient.group = None;
+    if next == 0 {
+        app.recipient.valid = false;
+    } else if next <= app.groups.len() {
+        app.recipient.valid = false;
+        app.recipient.group = Some(app.groups[next - 1].id);
+    } else {
+        let index = next - 1 - app.groups.len();
+        app.recipient.index = index;
+        app.recipient.peer = app.lan.peers[index].clone();
+        app.recipient.valid = true;
+    }
+}
+
+/// Queued if the recipient is gone, to be sent when it comes back.
 fn send(app: &mut App, content: String) {
-    if app.recipient.valid {
-        if app.status.is_error {
-            set_status(app, false, "");
-        }
-
-        let message_id = next_message_id(app);
-        let timestamp = now_fmt(app);
-
-        app.messages.push(Message {
-            timestamp,
-            direction: MessageType::Sending,
-            name: app.recipient.peer.name.clone(),
-            content: content.clone(),
-            message_id,
-        });
-
-        if let Err(_) = message_to_net(app, ToNet::Send {
-            message_id,
-            address: app.recipient.peer.address,
-            content,
-        }) {
+    if app.recipient.everyone {
+        broadcast(app, content);
+        return;
+    }
+    if let Some(id) = app.recipient.group {
+        send_group(app, id, content);
+        return;
+    }
+    if app.recipient.peer.name.is_empty() {
+        return;
+    }
+    if app.status.is_error {
+        set_status(app, false, "");
+    }
+
+    let message_id = next_message_id(app);
+    let timestamp = now_fmt(app);
+    let queued = !app.recipient.valid;
+    // The peer stops showing the user as typing when the message gets there.
+    app.typing_sent = None;
+
+    app.messages.push(Message {
+        timestamp,
+        direction: if queued { MessageType::Queued } else { MessageType::Sending },
+        name: app.recipient.peer.name.clone(),
+        content: content.clone(),
+        message_id,
+        transfer: None,
+        receipt: None,
+        origin: None,
+        revision: Revision::Original,
+    });
+    app.outbox.push(Outbound {
+        message_id,
+        name: app.recipient.peer.name.clone(),
+        identity: app.recipient.peer.identity.clone(),
+        address: app.recipient.peer.address,
+        content: content.clone(),
+        queued,
+    });
+    if queued {
+        set_status(app, false, format!("queued until {} comes back - /unqueue to drop it",
+            app.recipient.peer.name));
+        return;
+    }
+
+    if message_to_net(app, ToNet::Send {
+        message_id,
+        address: app.recipient.peer.address,
+        content,
+    }).is_err() {
+        app.outbox.retain(|a| a.message_id != message_id);
+        update_message(app, message_id, MessageType::SendFailed);
+        show_error(app, "async thread not started".into());
+    }
+}
+
+/// Sends to all of the peers that the network thread knows about. Not queued for peers that are
+/// gone.
+fn broadcast(app: &mut App, content: String) {
+    if app.status.is_error {
+        set_status(app, false, "");
+    }
+
+    let message_id = next_message_id(app);
+    let timestamp = now_fmt(app);
+
+    app.messages.push(Message {
+        timestamp,
+        direction: MessageType::Sending,
+        name: EVERYONE.into(),
+        content: content.clone(),
+        message_id,
+        transfer: None,
+        receipt: None,
+        origin: None,
+        revision: Revision::Original,
+    });
+
+    if message_to_net(app, ToNet::Broadcast {message_id, content}).is_err() {
+        update_message(app, message_id, MessageType::SendFailed);
+        show_error(app, "async thread not started".into());
+    }
+}
+
+/// Sent to the members that the network thread knows about. Not queued for members that are gone.
+fn send_group(app: &mut App, id: u64, content: String) {
+    let group = match app.groups.iter().find(|a| a.id == id) {
+        Some(a) => a.clone(),
+        None => {
+            set_status(app, true, "that group is gone");
+            return;
+        }
+    };
+    if app.status.is_error {
+        set_status(app, false, "");
+    }
+
+    let message_id = next_message_id(app);
+    let timestamp = now_fmt(app);
+
+    app.messages.push(Message {
+        timestamp,
+        direction: MessageType::Sending,
+        name: format!("[{}]", group.name),
+        content: content.clone(),
+        message_id,
+        transfer: None,
+        receipt: None,
+        origin: None,
+        revision: Revision::Original,
+    });
+
+    if message_to_net(app, ToNet::SendGroup {message_id, group, content}).is_err() {
+        update_message(app, message_id, MessageType::SendFailed);
+        show_error(app, "async thread not started".into());
+    }
+}
+
+/// Sends the queued messages for the peer at the address, if it's the one they were for.
+fn send_queued(app: &mut App, address: SocketAddr) {
+    let peer = match app.lan.peers.iter().find(|a| a.address == address) {
+        Some(a) => a.clone(),
+        None => return,
+    };
+    let mut sends = vec![];
+    for outbound in &mut app.outbox {
+        if outbound.queued && outbound.is_for(&peer.name, peer.identity.as_deref(), address) {
+            outbound.queued = false;
+            sends.push((outbound.message_id, outbound.content.clone()));
+        }
+    }
+
+    for (message_id, content) in sends {
+        update_message(app, message_id, MessageType::Sending);
+        if message_to_net(app, ToNet::Send {message_id, address, content}).is_err() {
+            app.outbox.retain(|a| a.message_id != message_id);
             update_message(app, message_id, MessageType::SendFailed);
             show_error(app, "async thread not started".into());
         }
     }
 }
 
+/// Whether the peer is the recipient that went missing, even if it came back at a different
+/// address.
+fn is_recipient(app: &App, peer: &Peer) -> bool {
+    let recipient = &app.recipient.peer;
+    match &recipient.identity {
+        Some(a) => peer.identity.as_ref() == Some(a),
+        None => recipient.name == peer.name,
+    }
+}
+
 fn paste(app: &mut App) -> Result<(), Box<dyn Error>> {
     let mut ctx: ClipboardContext = ClipboardProvider::new()?;
     let mut stuff = ctx.get_contents()?;
@@ -304,7 +1021,7 @@ fn start_logging(app: &mut App) {
     match app.logging {
         LogState::Pending | LogState::Active => {}
         LogState::Inactive => {
-            if let Err(_) = message_to_net(app, ToNet::LogStart) {
+            if message_to_net(app, ToNet::LogStart).is_err() {
                 show_error(app, "async thread not started".into());
                 return;
             }
@@ -313,3 +1030,27 @@ fn start_logging(app: &mut App) {
         }
     }
 }
+
+pub fn set_presence(app: &mut App, presence: Presence, note: String) {
+    app.presence = presence;
+    app.note = note;
+    app.auto_away = false;
+    let message = ToNet::SetPresence {presence, note: app.note.clone()};
+    if message_to_net(app, message).is_err() {
+        show_error(app, "async thread not started".into());
+    }
+    app.needs_redraw = true;
+}
+
+/// Goes away after `away_after` minutes without a key press, unless the user already picked
+/// something other than available.
+pub fn check_idle(app: &mut App) {
+    if app.config.away_after == 0 || app.presence != Presence::Available {
+        return;
+    }
+    let since = *app.last_input.get_or_insert_with(Instant::now);
+    if since.elapsed() >= Duration::from_secs(app.config.away_after * 60) {
+        set_presence(app, Presence::Away, app.note.clone());
+        app.auto_away = true;
+    }
+}
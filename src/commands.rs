@@ -0,0 +1,773 @@
+use std::net::SocketAddr;
+use std::fs::{metadata, canonicalize};
+use std::mem::take;
+use rand::random;
+use crate::data::{App, InputMode, Peer, Transfer, TransferState, MessageType, set_status,
+                  display_name};
+use crate::network::{ToNet, message_to_net, current_policy};
+use crate::config::{room_name, check_room, save_setting, parse_switch};
+use crate::ping::{Presence, MAX_NOTE_LEN, MAX_ROOMS};
+use crate::actions::{show_error, show_note, recheck_identities, peer_name, remove_peer,
+                     set_presence, show_transfer, update_transfer, update_message, store_group,
+                     remove_group};
+use crate::known_peers::{save_known_peers, pin_verified, peer_key, known_peer_mut, KnownPeer};
+use crate::identity::{pairing_code, short_fingerprint};
+use crate::render::presence_label;
+use crate::groups::{Group, MAX_MEMBERS, MAX_GROUP_NAME_LEN};
+
+/// Usage and description of each command, for `help`.
+const COMMANDS: &[(&str, &str)] = &[
+    ("add <host[:port]>", "connect to a computer that isn't found automatically"),
+    ("name [name]", "pick the name that other computers see, or go back to the host name"),
+    ("info [name]", "show the details of a computer, or of the selected one"),
+    ("send <path>", "offer a file or folder to the selected computer"),
+    ("receive [name]", "save the oldest file or folder that a computer offered"),
+    ("decline [name]", "turn down the oldest file or folder that a computer offered"),
+    ("cancel [name]", "stop the latest transfer to or from a computer"),
+    ("unqueue [name]", "drop the messages waiting for a computer to come back, or all of them"),
+    ("group <group>: <name>, ...", "make a group of computers, or change who's in it"),
+    ("ungroup <group>", "leave a group"),
+    ("groups", "list the groups and who's in them"),
+    ("forget <name>", "accept a new key or name for a computer that changed"),
+    ("pair <name>", "compare codes with a computer to make sure it's the right one"),
+    ("confirm", "accept the pairing code that was just shown"),
+    ("requests", "list the computers waiting for their messages to be accepted"),
+    ("accept <name>", "show the messages a computer has sent so far"),
+    ("always <name>", "always show messages from a computer"),
+    ("reject <name>", "throw away the messages waiting from a computer"),
+    ("block <name>", "hide a computer and ignore its messages"),
+    ("unblock <name>", "undo block"),
+    ("mute <name>", "keep showing a computer's messages but don't count them as unread"),
+    ("unmute <name>", "undo mute"),
+    ("blocked", "list the blocked and muted computers"),
+    ("available [note]", "tell the other computers that you're around"),
+    ("away [note]", "tell the other computers that you're away"),
+    ("busy [note]", "tell the other computers not to disturb you"),
+    ("receipts <on|off>", "choose whether other computers are told when you've seen messages"),
+    ("join <room>", "also show the computers in a room"),
+    ("part <room>", "stop showing the computers in a room"),
+    ("diagnostics", "show how much discovery traffic was thrown away"),
+    ("help", "show this list"),
+];
+
+pub fn run_command(app: &mut App, line: &str) {
+    let line = line.trim();
+    let (name, rest) = match line.split_once(char::is_whitespace) {
+        Some((name, rest)) => (name, rest.trim()),
+        None => (line, ""),
+    };
+
+    // A pairing code is only good until the user moves on to something else.
+    if name != "confirm" {
+        app.pairing = None;
+    }
+
+    match name {
+        "" => {}
+        "add" => add_peer(app, rest),
+        "name" => rename(app, rest),
+        "info" => info(app, rest),
+        "send" => send_file(app, rest),
+        "receive" => receive_file(app, rest),
+        "decline" => decline_file(app, rest),
+        "cancel" => cancel_file(app, rest),
+        "unqueue" => unqueue(app, rest),
+        "group" => make_group(app, rest),
+        "ungroup" => leave_group(app, rest),
+        "groups" => list_groups(app),
+        "forget" => forget(app, rest),
+        "pair" => pair(app, rest),
+        "confirm" => confirm(app),
+        "requests" => list_requests(app),
+        "accept" => accept(app, rest, false),
+        "always" => accept(app, rest, true),
+        "reject" => reject(app, rest),
+        "block" => block(app, rest),
+        "unblock" => set_flag(app, rest, |a| &mut a.blocked, false),
+        "mute" => mute(app, rest),
+        "unmute" => set_flag(app, rest, |a| &mut a.muted, false),
+        "blocked" => list_blocked(app),
+        "available" | "away" | "busy" => presence(app, name, rest),
+        "receipts" => read_receipts(app, rest),
+        "join" => join(app, rest),
+        "part" => part(app, rest),
+        "diagnostics" => diagnostics(app),
+        "help" => help(app),
+        _ => set_status(app, true, format!("unknown command: {} - try help", name)),
+    }
+}
+
+fn help(app: &mut App) {
+    let mut content = "Commands:".to_string();
+    for (usage, description) in COMMANDS {
+        content.push_str(&format!("\n  /{} - {}", usage, description));
+    }
+    show_note(app, content);
+}
+
+fn add_peer(app: &mut App, spec: &str) {
+    if spec.is_empty() || spec.contains(char::is_whitespace) {
+        set_status(app, true, "usage: add <host[:port]>");
+        return;
+    }
+
+    if message_to_net(app, ToNet::AddPeer(spec.to_string())).is_err() {
+        show_error(app, "async thread not started".into());
+        return;
+    }
+    set_status(app, false, format!("connecting to {}", spec));
+}
+
+/// Unpins the name so that whatever key it has next gets pinned to it instead.
+fn forget(app: &mut App, name: &str) {
+    if name.is_empty() {
+        set_status(app, true, "usage: forget <name>");
+        return;
+    }
+
+    let forgotten = app.known_peers.iter()
+        .filter(|a| a.is_pin() && a.name == name)
+        .map(|a| a.fingerprint.clone())
+        .collect::<Vec<_>>();
+    if forgotten.is_empty() {
+        set_status(app, true, format!("{} isn't pinned", name));
+        return;
+    }
+    app.known_peers.retain(|a| !forgotten.contains(&a.fingerprint));
+    if let Err(error) = save_known_peers(&app.known_peers) {
+        show_error(app, format!("error: {}", error));
+        return;
+    }
+
+    // Pins the current ones right away.
+    recheck_identities(app, name, &forgotten);
+    update_policy(app);
+    set_status(app, false, format!("forgot {}", name));
+}
+
+/// Finds a peer by name, or by `name:port` when there are several with the same name.
+fn find_peer(app: &mut App, label: &str) -> Option<Peer> {
+    let found = app.lan.peers.iter()
+        .filter(|a| a.name == label || format!("{}:{}", a.name, a.address.port()) == label)
+        .collect::<Vec<_>>();
+    match found[..] {
+        [peer] => Some(peer.clone()),
+        [] => {
+            set_status(app, true, format!("{} isn't on the network", label));
+            None
+        }
+        _ => {
+            set_status(app, true, format!("there are several called {} - add the port", label));
+            None
+        }
+    }
+}
+
+/// Shows the pairing code and puts `confirm` in the command prompt for the user to run if it
+/// matches the code on the other computer.
+fn pair(app: &mut App, label: &str) {
+    if label.is_empty() {
+        set_status(app, true, "usage: pair <name>");
+        return;
+    }
+    let peer = match find_peer(app, label) {
+        Some(a) => a,
+        None => return,
+    };
+    let identity = match &peer.identity {
+        Some(a) => a,
+        None => {
+            set_status(app, true, format!("{} hasn't proven its identity yet", label));
+            return;
+        }
+    };
+    if app.lan.local_identity.is_empty() {
+        set_status(app, true, "this computer's identity isn't loaded yet");
+        return;
+    }
+
+    let code = pairing_code(&app.lan.local_identity, identity);
+    show_note(app, format!(
+        "Pairing code for {}: {}\nType /pair {} on {} and check that it shows the same code. \
+        Press Enter if it does or Esc if it doesn't.",
+        label, code, display_name(app), label));
+    app.pairing = Some(peer);
+    app.command = "confirm".into();
+    app.input_mode = InputMode::Command;
+}
+
+fn confirm(app: &mut App) {
+    let peer = match app.pairing.take() {
+        Some(a) => a,
+        None => {
+            set_status(app, true, "use pair <name> first");
+            return;
+        }
+    };
+    let identity = match peer.identity {
+        Some(a) => a,
+        None => return,
+    };
+
+    pin_verified(&mut app.known_peers, &identity, &peer.name);
+    if let Err(error) = save_known_peers(&app.known_peers) {
+        show_error(app, format!("error: {}", error));
+    }
+    recheck_identities(app, &peer.name, &[identity]);
+    update_policy(app);
+    show_note(app, format!("{} is verified.", peer.name));
+}
+
+fn update_policy(app: &mut App) {
+    let policy = current_policy(app);
+    if message_to_net(app, ToNet::SetPolicy(policy)).is_err() {
+        show_error(app, "async thread not started".into());
+    }
+}
+
+/// Finds a message request by the name of its peer, `name:port` or the address.
+fn find_request(app: &mut App, label: &str) -> Option<SocketAddr> {
+    if label.is_empty() {
+        set_status(app, true, "which computer?");
+        return None;
+    }
+    let found = app.requests.iter()
+        .map(|a| a.address)
+        .filter(|&address| {
+            let name = peer_name(app, address);
+            name == label || format!("{}:{}", name, address.port()) == label
+                || address.to_string() == label
+        })
+        .collect::<Vec<_>>();
+    match found[..] {
+        [address] => Some(address),
+        [] => {
+            set_status(app, true, format!("no messages waiting from {}", label));
+            None
+        }
+        _ => {
+            set_status(app, true, format!("there are several called {} - add the port", label));
+            None
+        }
+    }
+}
+
+fn list_requests(app: &mut App) {
+    if app.requests.is_empty() {
+        set_status(app, false, "no messages waiting");
+        return;
+    }
+    let mut content = "Waiting to be accepted:".to_string();
+    for request in &app.requests {
+        content.push_str(&format!("\n  {} ({}) - {} message(s)", peer_name(app, request.address),
+            request.address, request.count));
+    }
+    show_note(app, content);
+}
+
+/// `always` = remember the choice for next time too
+fn accept(app: &mut App, label: &str, always: bool) {
+    let address = match find_request(app, label) {
+        Some(a) => a,
+        None => return,
+    };
+    app.requests.retain(|a| a.address != address);
+
+    if always {
+        let found = app.lan.peers.iter().find(|a| a.address == address);
+        match found {
+            Some(peer) if peer.mismatch => {
+                show_error(app, format!("{}'s identity doesn't match the one it had before. \
+                    Use /pair or /forget first. Only the messages waiting now were accepted.",
+                    label));
+            }
+            Some(Peer {identity: Some(identity), ..}) => {
+                let identity = identity.clone();
+                for known in app.known_peers.iter_mut().filter(|a| a.fingerprint == identity) {
+                    known.approved = true;
+                }
+                if let Err(error) = save_known_peers(&app.known_peers) {
+                    show_error(app, format!("error: {}", error));
+                }
+                update_policy(app);
+            }
+            _ => {
+                show_error(app, format!("{} is running an older version of sclan so it can't be \
+                    recognized next time. Only the messages waiting now were accepted.",
+                    label));
+            }
+        }
+    }
+
+    if message_to_net(app, ToNet::Accept(address)).is_err() {
+        show_error(app, "async thread not started".into());
+    }
+}
+
+fn reject(app: &mut App, label: &str) {
+    let address = match find_request(app, label) {
+        Some(a) => a,
+        None => return,
+    };
+    app.requests.retain(|a| a.address != address);
+
+    if message_to_net(app, ToNet::Reject(address)).is_err() {
+        show_error(app, "async thread not started".into());
+        return;
+    }
+    set_status(app, false, format!("rejected {}", label));
+}
+
+fn save_flags(app: &mut App) {
+    if let Err(error) = save_known_peers(&app.known_peers) {
+        show_error(app, format!("error: {}", error));
+    }
+    update_policy(app);
+}
+
+fn block(app: &mut App, label: &str) {
+    if label.is_empty() {
+        set_status(app, true, "usage: block <name>");
+        return;
+    }
+    let peer = match find_peer(app, label) {
+        Some(a) => a,
+        None => return,
+    };
+
+    let key = peer_key(peer.identity.as_deref(), peer.address);
+    known_peer_mut(&mut app.known_peers, &key, &peer.name).blocked = true;
+    save_flags(app);
+
+    remove_peer(app, peer.address);
+    app.requests.retain(|a| a.address != peer.address);
+    set_status(app, false, format!("blocked {} - /unblock {} to undo", label, peer.name));
+}
+
+fn mute(app: &mut App, label: &str) {
+    if label.is_empty() {
+        set_status(app, true, "usage: mute <name>");
+        return;
+    }
+    let peer = match find_peer(app, label) {
+        Some(a) => a,
+        None => return,
+    };
+
+    let key = peer_key(peer.identity.as_deref(), peer.address);
+    known_peer_mut(&mut app.known_peers, &key, &peer.name).muted = true;
+    save_flags(app);
+    set_status(app, false, format!("muted {}", label));
+}
+
+/// For undoing block and mute. Goes by the saved name since blocked peers aren't in the list.
+fn set_flag(app: &mut App, name: &str, flag: fn(&mut KnownPeer) -> &mut bool, value: bool) {
+    let mut found = false;
+    for known in app.known_peers.iter_mut().filter(|a| a.name == name) {
+        let flag = flag(known);
+        found |= *flag != value;
+        *flag = value;
+    }
+    if !found {
+        set_status(app, true, format!("nothing to undo for {}", name));
+        return;
+    }
+    save_flags(app);
+    set_status(app, false, format!("done - {} will show up again once it's heard from", name));
+}
+
+fn list_blocked(app: &mut App) {
+    let mut content = String::new();
+    for known in app.known_peers.iter().filter(|a| a.blocked || a.muted) {
+        let state = if known.blocked { "blocked" } else { "muted" };
+        content.push_str(&format!("\n  {} - {}", known.name, state));
+    }
+    if content.is_empty() {
+        set_status(app, false, "nothing blocked or muted");
+        return;
+    }
+    show_note(app, format!("Blocked and muted:{}", content));
+}
+
+fn join(app: &mut App, room: &str) {
+    let room = room_name(room);
+    if room.is_empty() {
+        set_status(app, true, "usage: join <room>");
+        return;
+    }
+    if let Err(error) = check_room(&room) {
+        set_status(app, true, error);
+        return;
+    }
+    if app.config.rooms.contains(&room) {
+        set_status(app, true, format!("already in #{}", room));
+        return;
+    }
+    if app.config.rooms.len() >= MAX_ROOMS {
+        set_status(app, true, format!("can't be in more than {} rooms - part one first",
+            MAX_ROOMS));
+        return;
+    }
+    app.config.rooms.push(room.clone());
+    update_rooms(app);
+    set_status(app, false, format!("joined #{}", room));
+}
+
+fn part(app: &mut App, room: &str) {
+    let room = room_name(room);
+    if !app.config.rooms.contains(&room) {
+        set_status(app, true, format!("not in #{}", room));
+        return;
+    }
+    app.config.rooms.retain(|a| *a != room);
+    update_rooms(app);
+    set_status(app, false, format!("left #{}", room));
+}
+
+fn update_rooms(app: &mut App) {
+    if message_to_net(app, ToNet::SetRooms(app.config.rooms.clone())).is_err() {
+        show_error(app, "async thread not started".into());
+    }
+}
+
+fn presence(app: &mut App, name: &str, note: &str) {
+    let presence = match Presence::from_name(name) {
+        Some(a) => a,
+        None => return,
+    };
+    if note.len() > MAX_NOTE_LEN {
+        set_status(app, true, format!("the note can't be longer than {} bytes", MAX_NOTE_LEN));
+        return;
+    }
+    set_presence(app, presence, note.to_string());
+    set_status(app, false, format!("you're {}", presence.name()));
+}
+
+/// Saved in the config file so that it's kept next time.
+fn rename(app: &mut App, name: &str) {
+    if name.len() > u8::MAX as usize {
+        set_status(app, true, format!("names can't be longer than {} bytes", u8::MAX));
+        return;
+    }
+    app.config.name = name.to_string();
+    if let Err(error) = save_setting("name", Some(name).filter(|a| !a.is_empty())) {
+        show_error(app, format!("error: {}", error));
+    }
+    if message_to_net(app, ToNet::SetName(name.to_string())).is_err() {
+        show_error(app, "async thread not started".into());
+        return;
+    }
+    set_status(app, false, format!("other computers will see {}", display_name(app)));
+}
+
+/// Saved in the config file so that it's kept next time. Messages that were already shown but not
+/// seen yet don't get read receipts after it's turned off.
+fn read_receipts(app: &mut App, value: &str) {
+    let on = match parse_switch(value) {
+        Ok(a) => a,
+        Err(error) => {
+            set_status(app, true, error);
+            return;
+        }
+    };
+    app.config.read_receipts = on;
+    if !on {
+        for message in &mut app.messages {
+            message.receipt = None;
+        }
+    }
+    if let Err(error) = save_setting("read-receipts", Some(if on { "on" } else { "off" })) {
+        show_error(app, format!("error: {}", error));
+    }
+    if on {
+        set_status(app, false, "other computers will be told when you've seen their messages");
+    } else {
+        set_status(app, false, "other computers won't be told when you've seen their messages");
+    }
+}
+
+fn info(app: &mut App, label: &str) {
+    let peer = if !label.is_empty() {
+        match find_peer(app, label) {
+            Some(a) => a,
+            None => return,
+        }
+    } else if app.recipient.valid {
+        app.recipient.peer.clone()
+    } else {
+        set_status(app, true, "usage: info <name>");
+        return;
+    };
+    // The copy in the recipient isn't kept up to date.
+    let peer = app.lan.peers.iter().find(|a| a.address == peer.address).unwrap_or(&peer);
+
+    let mut content = format!("{}\n  address: {}", peer.name, peer.address);
+    if let Some(hostname) = &peer.hostname {
+        content.push_str(&format!("\n  host name: {}", hostname));
+    }
+    let identity = match &peer.identity {
+        Some(a) if peer.verified => format!("{} (paired)", short_fingerprint(a)),
+        Some(a) => short_fingerprint(a),
+        None => "unknown".to_string(),
+    };
+    content.push_str(&format!("\n  identity: {}", identity));
+    if peer.presence.is_some() {
+        let presence = presence_label(peer.presence, &peer.note);
+        let presence = if presence.is_empty() { "available".to_string() } else { presence };
+        content.push_str(&format!("\n  presence: {}", presence.trim()));
+    }
+    if !peer.rooms.is_empty() {
+        let rooms = peer.rooms.iter().map(|a| format!("#{}", a)).collect::<Vec<_>>();
+        content.push_str(&format!("\n  rooms: {}", rooms.join(" ")));
+    }
+    show_note(app, content);
+}
+
+fn diagnostics(app: &mut App) {
+    let counts = app.rejected;
+    let mut content = format!(
+        "Thrown away since starting:\n  invalid pings: {}\n  flooding pings: {}\n  \
+        peers over the limit: {}",
+        counts.invalid, counts.flooding, counts.too_many);
+    if let Some(source) = counts.last_source {
+        content.push_str(&format!("\n  last from: {}", source));
+    }
+    show_note(app, content);
+}
+
+fn send_file(app: &mut App, path: &str) {
+    // Paths copied from a file manager often come with quotes around them.
+    let path = path.trim_matches('"');
+    if path.is_empty() {
+        set_status(app, true, "usage: send <path>");
+        return;
+    }
+    if !app.recipient.valid {
+        set_status(app, true, "pick a computer to send it to with Tab first");
+        return;
+    }
+    // A folder's size is only known once the network thread has looked through it.
+    let (size, folder) = match metadata(path) {
+        Ok(a) if a.is_file() => (Some(a.len()), false),
+        Ok(a) if a.is_dir() => (None, true),
+        Ok(_) => {
+            set_status(app, true, format!("{} isn't a file or folder", path));
+            return;
+        }
+        Err(error) => {
+            set_status(app, true, format!("{}: {}", path, error));
+            return;
+        }
+    };
+
+    let id = random();
+    let address = app.recipient.peer.address;
+    let name = canonicalize(path).ok()
+        .and_then(|a| a.file_name().map(|a| a.to_string_lossy().into_owned()))
+        .unwrap_or_default();
+    show_transfer(app, Transfer {
+        id,
+        address,
+        name,
+        size,
+        folder,
+        outgoing: true,
+        state: TransferState::Offered,
+    });
+    if message_to_net(app, ToNet::SendFile {id, address, path: path.into()}).is_err() {
+        update_transfer(app, id, TransferState::Failed("async thread not started".into()));
+    }
+}
+
+/// The transfers that pass the filter, oldest first, with the computer called `label` or with
+/// any computer if it's empty.
+fn find_transfers(app: &App, label: &str, filter: fn(&Transfer) -> bool) -> Vec<u64> {
+    app.transfers.iter()
+        .filter(|a| filter(a))
+        .filter(|a| {
+            let name = peer_name(app, a.address);
+            label.is_empty() || name == label || format!("{}:{}", name, a.address.port()) == label
+        })
+        .map(|a| a.id)
+        .collect()
+}
+
+fn is_offer(transfer: &Transfer) -> bool {
+    !transfer.outgoing && transfer.state == TransferState::Offered
+}
+
+fn receive_file(app: &mut App, label: &str) {
+    let id = match find_transfers(app, label, is_offer).first() {
+        Some(&a) => a,
+        None => {
+            set_status(app, true, "no files waiting to be received");
+            return;
+        }
+    };
+    if message_to_net(app, ToNet::AcceptFile(id)).is_err() {
+        show_error(app, "async thread not started".into());
+        return;
+    }
+    update_transfer(app, id, TransferState::Running(0));
+}
+
+fn decline_file(app: &mut App, label: &str) {
+    let id = match find_transfers(app, label, is_offer).first() {
+        Some(&a) => a,
+        None => {
+            set_status(app, true, "no files waiting to be received");
+            return;
+        }
+    };
+    if message_to_net(app, ToNet::CancelFile(id)).is_err() {
+        show_error(app, "async thread not started".into());
+        return;
+    }
+    update_transfer(app, id, TransferState::Failed("declined".into()));
+}
+
+/// Offers that this computer made can be taken back too.
+fn cancel_file(app: &mut App, label: &str) {
+    let found = find_transfers(app, label, |a| match a.state {
+        TransferState::Offered => a.outgoing,
+        TransferState::Running(_) => true,
+        _ => false,
+    });
+    let id = match found.last() {
+        Some(&a) => a,
+        None => {
+            set_status(app, true, "no files being sent or received");
+            return;
+        }
+    };
+    if message_to_net(app, ToNet::CancelFile(id)).is_err() {
+        show_error(app, "async thread not started".into());
+        return;
+    }
+    update_transfer(app, id, TransferState::Failed("cancelled".into()));
+}
+
+/// Goes by the name that the messages were written to, since the computer isn't around to pick.
+fn unqueue(app: &mut App, name: &str) {
+    let (dropped, kept): (Vec<_>, Vec<_>) = take(&mut app.outbox).into_iter()
+        .partition(|a| a.queued && (name.is_empty() || a.name == name));
+    app.outbox = kept;
+    if dropped.is_empty() {
+        set_status(app, true, "no messages are waiting");
+        return;
+    }
+    for outbound in &dropped {
+        update_message(app, outbound.message_id, MessageType::SendFailed);
+    }
+    set_status(app, false, format!("dropped the messages that were waiting: {}", dropped.len()));
+}
+
+/// Shares the group with the computers in it, and with the ones taken out of it so that they
+/// know. Only computers that proved their identities can be in groups.
+fn make_group(app: &mut App, line: &str) {
+    let (name, labels) = match line.split_once(':') {
+        Some((name, labels)) if !name.trim().is_empty() => (name.trim(), labels),
+        _ => {
+            set_status(app, true, "expected a group name, a colon and a list of computers");
+            return;
+        }
+    };
+    if name.len() > MAX_GROUP_NAME_LEN {
+        set_status(app, true, format!("group names can't be longer than {} bytes",
+            MAX_GROUP_NAME_LEN));
+        return;
+    }
+    if app.lan.local_identity.is_empty() {
+        set_status(app, true, "this computer doesn't have an identity yet");
+        return;
+    }
+
+    let mut members = vec![app.lan.local_identity.clone()];
+    for label in labels.split(',').map(|a| a.trim()).filter(|a| !a.is_empty()) {
+        let peer = match find_peer(app, label) {
+            Some(a) => a,
+            None => return,
+        };
+        let identity = match peer.identity {
+            Some(a) => a,
+            None => {
+                set_status(app, true, format!("{} hasn't proven its identity, so it can't be \
+                    in a group", label));
+                return;
+            }
+        };
+        if !members.contains(&identity) {
+            members.push(identity);
+        }
+    }
+    if members.len() > MAX_MEMBERS {
+        set_status(app, true, format!("groups can't have more than {} computers", MAX_MEMBERS));
+        return;
+    }
+
+    let found = app.groups.iter().find(|a| a.name == name);
+    let (id, mut to) = match found {
+        Some(group) => (group.id, group.members.clone()),
+        None => (random(), vec![]),
+    };
+    for member in &members {
+        if !to.contains(member) {
+            to.push(member.clone());
+        }
+    }
+    let group = Group {id, name: name.to_string(), members};
+    store_group(app, group.clone());
+    if message_to_net(app, ToNet::ShareGroup {group, to}).is_err() {
+        show_error(app, "async thread not started".into());
+        return;
+    }
+    set_status(app, false, format!("[{}] is ready - press [Tab] to pick it", name));
+}
+
+/// Tells the rest of the group so that they stop sending to this computer.
+fn leave_group(app: &mut App, name: &str) {
+    let mut group = match app.groups.iter().find(|a| a.name == name) {
+        Some(a) => a.clone(),
+        None => {
+            set_status(app, true, format!("there's no group called {}", name));
+            return;
+        }
+    };
+    remove_group(app, group.id);
+    group.members.retain(|a| *a != app.lan.local_identity);
+    let to = group.members.clone();
+    if message_to_net(app, ToNet::ShareGroup {group, to}).is_err() {
+        show_error(app, "async thread not started".into());
+        return;
+    }
+    set_status(app, false, format!("left [{}]", name));
+}
+
+fn list_groups(app: &mut App) {
+    if app.groups.is_empty() {
+        show_note(app, "There are no groups. Make one with /group.".into());
+        return;
+    }
+    let mut content = "Groups:".to_string();
+    for group in &app.groups {
+        let members = group.members.iter()
+            .filter(|a| **a != app.lan.local_identity)
+            .map(|a| member_name(app, a))
+            .collect::<Vec<_>>();
+        content.push_str(&format!("\n  [{}] - {}", group.name, members.join(", ")));
+    }
+    show_note(app, content);
+}
+
+/// The name of the peer with the fingerprint, from the ones on the network or the ones seen
+/// before.
+fn member_name(app: &App, fingerprint: &str) -> String {
+    let found = app.lan.peers.iter().find(|a| a.identity.as_deref() == Some(fingerprint));
+    if let Some(peer) = found {
+        return peer.name.clone();
+    }
+    match app.known_peers.iter().find(|a| a.fingerprint == fingerprint) {
+        Some(known) => known.name.clone(),
+        None => short_fingerprint(fingerprint),
+    }
+}
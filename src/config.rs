@@ -0,0 +1,221 @@
+use std::env::args;
+use std::fs::{read_to_string, write};
+use std::io::ErrorKind;
+use crate::ping::{MAX_ROOMS, MAX_ROOM_NAME_LEN};
+
+const CONFIG_DEST: &str = "./sclan.conf";
+
+pub const USAGE: &str = "\
+usage: sclan [options]
+
+Options can also be put in a sclan.conf file in the current directory, one per line, written
+as `name = value` without the leading dashes.
+
+    --approved-only [on|off]        Hold messages from computers that haven't been approved yet
+                                    and ask before showing them. Off by default.
+    --away-after <minutes>          Show as away to the other computers after this long without
+                                    a key being pressed. 0 turns it off. Defaults to 10.
+    --interface <name or address>   Only use this network interface for finding other computers.
+                                    Can be given more than once or as a comma-separated list.
+    --mdns [on|off]                 Also find other computers with multicast DNS, for networks
+                                    that block broadcasts but allow mDNS. Off by default.
+    --name <name>                   Name to show to the other computers instead of the host name.
+    --peer <host[:port]>            Connect to this computer directly instead of waiting to find
+                                    it on the network. Can be given more than once.
+    --port <port>                   Port to receive messages on. Other computers need to know it
+                                    when adding this one by hand. Defaults to 31332.
+    --read-receipts [on|off]        Tell the other computers when their messages have been seen.
+                                    On by default.
+    --room <name>                   Only show computers that joined the same room, for busy
+                                    networks. Can be given more than once or as a
+                                    comma-separated list to be in several rooms at once.";
+
+/// Port for receiving messages when none is configured. If it's taken, a random one is used.
+pub const DEFAULT_PORT: u16 = 31332;
+
+#[derive(Clone)]
+pub struct Config {
+    /// Names or addresses of the network interfaces to use for discovery. Empty means all of them.
+    pub interfaces: Vec<String>,
+    /// Peers to connect to without discovery, as `host` or `host:port`.
+    pub peers: Vec<String>,
+    pub port: u16,
+    /// Whether to advertise and browse for the service over multicast DNS.
+    pub mdns: bool,
+    /// Whether messages from peers that the user hasn't approved are held until the user decides.
+    pub approved_only: bool,
+    /// Discovery only shows peers in one of these. Empty means peers that aren't in any rooms.
+    pub rooms: Vec<String>,
+    /// Minutes without input before the user is shown as away. 0 means never.
+    pub away_after: u64,
+    /// Shown to the other computers instead of the host name. Empty to use the host name.
+    pub name: String,
+    /// Whether senders are told when their messages have been seen.
+    pub read_receipts: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            interfaces: vec![],
+            peers: vec![],
+            port: DEFAULT_PORT,
+            mdns: false,
+            approved_only: false,
+            rooms: vec![],
+            away_after: 10,
+            name: String::new(),
+            read_receipts: true,
+        }
+    }
+}
+
+/// Reads the config file and then applies the command line on top of it.
+pub fn load_config() -> Result<Config, String> {
+    let mut config = Config::default();
+
+    match read_to_string(CONFIG_DEST) {
+        Ok(text) => parse_file(&mut config, &text)?,
+        Err(error) if error.kind() == ErrorKind::NotFound => {}
+        Err(error) => return Err(format!("{}: {}", CONFIG_DEST, error)),
+    }
+
+    parse_args(&mut config, args().skip(1))?;
+    Ok(config)
+}
+
+/// Sets one option in the config file, or removes it when the value is None. The rest of the
+/// file is left the way the user wrote it.
+pub fn save_setting(key: &str, value: Option<&str>) -> Result<(), String> {
+    let text = match read_to_string(CONFIG_DEST) {
+        Ok(a) => a,
+        Err(error) if error.kind() == ErrorKind::NotFound => String::new(),
+        Err(error) => return Err(format!("{}: {}", CONFIG_DEST, error)),
+    };
+
+    let mut lines = vec![];
+    let mut saved = false;
+    for line in text.lines() {
+        let found = line.split_once('=').is_some_and(|(a, _)| a.trim() == key);
+        if !found {
+            lines.push(line.to_string());
+            continue;
+        }
+        // Repeats of the option are dropped so that they can't undo it.
+        if let (Some(value), false) = (value, saved) {
+            lines.push(format!("{} = {}", key, value));
+            saved = true;
+        }
+    }
+    if let (Some(value), false) = (value, saved) {
+        lines.push(format!("{} = {}", key, value));
+    }
+
+    let mut text = lines.join("\n");
+    text.push('\n');
+    write(CONFIG_DEST, text).map_err(|a| format!("{}: {}", CONFIG_DEST, a))
+}
+
+fn parse_file(config: &mut Config, text: &str) -> Result<(), String> {
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = line.split_once('=')
+            .ok_or(format!("{} line {}: expected `name = value`", CONFIG_DEST, number + 1))?;
+        apply(config, key.trim(), value.trim())
+            .map_err(|a| format!("{} line {}: {}", CONFIG_DEST, number + 1, a))?;
+    }
+    Ok(())
+}
+
+/// Options that can be given on the command line without a value to turn them on.
+const FLAGS: &[&str] = &["mdns", "approved-only", "read-receipts"];
+
+fn parse_args(config: &mut Config, args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut args = args.peekable();
+    while let Some(arg) = args.next() {
+        let key = arg.strip_prefix("--")
+            .ok_or(format!("unexpected argument: {}", arg))?;
+
+        let (key, value) = match key.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            // so that `--mdns` works the same as `--mdns on`
+            None if FLAGS.contains(&key) && !matches!(args.peek(), Some(a) if is_switch(a)) => {
+                (key.to_string(), "on".to_string())
+            }
+            None => {
+                let value = args.next().ok_or(format!("missing value for --{}", key))?;
+                (key.to_string(), value)
+            }
+        };
+        apply(config, &key, &value).map_err(|a| format!("--{}: {}", key, a))?;
+    }
+    Ok(())
+}
+
+fn apply(config: &mut Config, key: &str, value: &str) -> Result<(), String> {
+    match key {
+        "interface" => config.interfaces.extend(split_list(value)),
+        "name" => config.name = value.to_string(),
+        "peer" => config.peers.extend(split_list(value)),
+        "port" => config.port = value.parse().map_err(|_| format!("invalid port `{}`", value))?,
+        "mdns" => config.mdns = parse_switch(value)?,
+        "approved-only" => config.approved_only = parse_switch(value)?,
+        "read-receipts" => config.read_receipts = parse_switch(value)?,
+        "away-after" => {
+            config.away_after = value.parse()
+                .map_err(|_| format!("invalid number of minutes `{}`", value))?;
+        }
+        "room" => {
+            for room in split_list(value).map(|a| room_name(&a)) {
+                check_room(&room)?;
+                if !config.rooms.contains(&room) {
+                    if config.rooms.len() >= MAX_ROOMS {
+                        return Err(format!("can't be in more than {} rooms", MAX_ROOMS));
+                    }
+                    config.rooms.push(room);
+                }
+            }
+        }
+        _ => return Err(format!("unknown option `{}`", key)),
+    }
+    Ok(())
+}
+
+/// Rooms are matched without regard to case.
+pub fn room_name(value: &str) -> String {
+    value.trim().to_lowercase()
+}
+
+/// For a name from `room_name`. Commas separate rooms, so they can't be in one.
+pub fn check_room(room: &str) -> Result<(), String> {
+    if room.len() > MAX_ROOM_NAME_LEN {
+        return Err(format!("room names can't be longer than {} bytes", MAX_ROOM_NAME_LEN));
+    }
+    if room.contains(',') || room.chars().any(|a| a.is_control()) {
+        return Err("room names can't have commas or control characters in them".into());
+    }
+    Ok(())
+}
+
+fn is_switch(value: &str) -> bool {
+    parse_switch(value).is_ok()
+}
+
+pub fn parse_switch(value: &str) -> Result<bool, String> {
+    match value {
+        "on" | "true" | "yes" => Ok(true),
+        "off" | "false" | "no" => Ok(false),
+        _ => Err(format!("expected on or off, not `{}`", value)),
+    }
+}
+
+fn split_list(value: &str) -> impl Iterator<Item = String> + '_ {
+    value.split(',')
+        .map(|a| a.trim())
+        .filter(|a| !a.is_empty())
+        .map(|a| a.to_string())
+}
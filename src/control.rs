@@ -0,0 +1,94 @@
+//! Messages between copies of sclan that aren't shown to the user. They're sent in envelopes
+//! like the typed messages.
+
+use std::net::SocketAddr;
+use serde::{Deserialize, Serialize};
+
+pub const CHALLENGE_LEN: usize = 32;
+
+/// Longest file name that's offered. Longer ones are cut short.
+pub const MAX_FILE_NAME_LEN: usize = 255;
+pub const HASH_LEN: usize = 32;
+/// Most files and folders in a folder that's offered.
+pub const MAX_ENTRIES: usize = 100_000;
+
+#[derive(Serialize, Deserialize)]
+pub enum Control {
+    /// Starts the identity handshake. The other side answers with a proof and, if it hasn't
+    /// already, a hello of its own.
+    Hello {
+        /// Random bytes for the other side to sign.
+        challenge: [u8; CHALLENGE_LEN],
+        /// The other side's end of the connection as this side sees it, for signing too.
+        seen_at: SocketAddr,
+    },
+    /// Answers a hello. Carries the key along with the signature because messages can arrive out
+    /// of order, so it can't count on a hello having arrived first.
+    Proof {
+        public_key: Vec<u8>,
+        /// Over the challenge from the other side's hello and the addresses of both ends.
+        signature: Vec<u8>,
+        /// The other side's end of the connection as this side sees it. The other side checks
+        /// that it's really its own so that a proof can't be passed along from another
+        /// connection.
+        seen_at: SocketAddr,
+    },
+    /// The sender is closing, so it can be forgotten without waiting for its pings to stop. Also
+    /// covers peers that were added by hand and never send pings.
+    Leave,
+    File(FileControl),
+}
+
+/// A file or folder inside of a folder that's being sent.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// Relative to the folder being sent, with `/` between the parts. Not checked when received.
+    pub path: String,
+    pub dir: bool,
+    /// 0 for folders.
+    pub size: u64,
+    /// Unix permission bits. None from systems that don't have them.
+    pub mode: Option<u32>,
+}
+
+/// For sending files. A transfer is known by an id picked by the sender.
+#[derive(Serialize, Deserialize)]
+pub enum FileControl {
+    /// Asks the user on the other side whether to receive a file. Sent again with the same id to
+    /// pick up where a transfer left off after the connection broke.
+    Offer {
+        id: u64,
+        /// Of all of the files together for a folder.
+        size: u64,
+        /// Without the folder that it's in.
+        name: String,
+        /// What's in it if it's a folder. The contents of its files are sent one after the other
+        /// in this order as though they were one file.
+        folder: Option<Vec<Entry>>,
+    },
+    /// The user accepted the file, so the sender can start on the chunks.
+    Accept {
+        id: u64,
+        /// How much of the file the receiver already has. 0 unless the transfer was cut off. Not
+        /// checked on its own, only along with the rest by the hash at the end. It's kept in
+        /// memory, so a transfer can't be resumed after either side restarts.
+        offset: u64,
+    },
+    /// Either side giving up, or the receiver declining.
+    Cancel {
+        id: u64,
+    },
+    /// Part of the file. They're sent one after the other so the offset is only there to catch
+    /// ones left over from before the transfer was resumed.
+    Chunk {
+        id: u64,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// Follows the last chunk.
+    Finish {
+        id: u64,
+        /// SHA-256 of the whole file, for the receiver to check.
+        hash: [u8; HASH_LEN],
+    },
+}
@@ -1,19 +1,21 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::mpsc::{Sender, Receiver};
+use std::time::Instant;
 use time::macros::format_description;
 use time::{OffsetDateTime, UtcOffset};
-use crate::network::{ToNet, FromNet};
+use crate::network::{ToNet, FromNet, RejectedCounts};
+use crate::config::Config;
+use crate::known_peers::KnownPeer;
+use crate::groups::Group;
+use crate::ping::Presence;
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, PartialEq, Eq, Default)]
 pub enum InputMode {
+    #[default]
     Normal,
     Editing,
-}
-
-impl Default for InputMode {
-    fn default() -> InputMode {
-        InputMode::Normal
-    }
+    /// Typing one of the commands from `commands.rs`.
+    Command,
 }
 
 #[derive(Default)]
@@ -21,6 +23,8 @@ pub struct App {
     pub quitting: bool,
     pub input: String,
     pub input_mode: InputMode,
+    /// Kept apart from `input` so that a half-written message survives running a command.
+    pub command: String,
     pub messages: Vec<Message>,
     pub message_highlight: Option<u16>,
     pub lan: LANState,
@@ -31,27 +35,121 @@ pub struct App {
     pub last_message_id: u32,
     pub offset: Option<UtcOffset>,
     pub logging: LogState,
+    pub config: Config,
+    pub known_peers: Vec<KnownPeer>,
+    /// Shown as recipients after the entry for everyone.
+    pub groups: Vec<Group>,
+    /// The peer whose pairing code is being shown, until the user confirms or cancels it.
+    pub pairing: Option<Peer>,
+    /// Peers with messages waiting to be accepted or rejected.
+    pub requests: Vec<MessageRequest>,
+    /// Messages received since the user last pressed a key, not counting muted peers.
+    pub unread: usize,
+    /// What the other computers are told about whether the user is around.
+    pub presence: Presence,
+    pub note: String,
+    /// The presence was set to away because no keys were pressed, so it goes back to available
+    /// on the next one.
+    pub auto_away: bool,
+    /// None until the first key press. Counted from startup before that.
+    pub last_input: Option<Instant>,
+    /// Discovery traffic thrown away since startup, for `/diagnostics`.
+    pub rejected: RejectedCounts,
+    /// Files offered, being sent or received, and finished, in the order they came up.
+    pub transfers: Vec<Transfer>,
+    /// Messages that haven't reached their peers yet, in the order they were written.
+    pub outbox: Vec<Outbound>,
+    /// When queued messages were last tried again. None until the first time.
+    pub queue_tried: Option<Instant>,
+    /// Peers typing something for this computer and when they last said so.
+    pub typing: Vec<(SocketAddr, Instant)>,
+    /// The peer that was last told the user is typing for it, and when.
+    pub typing_sent: Option<(SocketAddr, Instant)>,
+    /// The sent message being edited in the input, and what was in the input before.
+    pub editing: Option<(u32, String)>,
+}
+
+/// A sent message that's on its way or waiting for its peer to come back.
+pub struct Outbound {
+    pub message_id: u32,
+    /// Of the peer, for finding it again if it comes back at a different address.
+    pub name: String,
+    pub identity: Option<String>,
+    /// Of the peer when the message was written.
+    pub address: SocketAddr,
+    pub content: String,
+    /// Waiting for the peer to come back, as opposed to on its way.
+    pub queued: bool,
+}
+
+impl Outbound {
+    /// Goes by the identity if the peer had proven one, so that another computer with the same
+    /// name doesn't get the messages. Peers without one have to come back with the same name from
+    /// the same IP, since a name alone is easy to take. The port can change when sclan restarts.
+    pub fn is_for(&self, name: &str, identity: Option<&str>, address: SocketAddr) -> bool {
+        match &self.identity {
+            Some(a) => identity == Some(a.as_str()),
+            None => self.name == name && self.address.ip() == address.ip(),
+        }
+    }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+/// A file or folder going to or coming from another computer. Shown as a message that's updated
+/// as it goes.
+pub struct Transfer {
+    pub id: u64,
+    /// Of the other computer.
+    pub address: SocketAddr,
+    /// Without the folder that it's in.
+    pub name: String,
+    /// Of all of the files together for a folder. None until a folder that's being sent has been
+    /// looked through.
+    pub size: Option<u64>,
+    pub folder: bool,
+    pub outgoing: bool,
+    pub state: TransferState,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum TransferState {
+    /// Waiting for the receiving user to accept.
+    Offered,
+    /// Bytes so far.
+    Running(u64),
+    /// Where a received file was saved.
+    Done(Option<String>),
+    Failed(String),
+}
+
+pub struct MessageRequest {
+    pub address: SocketAddr,
+    /// Number of messages being held.
+    pub count: usize,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Default)]
 pub enum LogState {
     Active,
+    #[default]
     Inactive,
     Pending,
 }
 
-impl Default for LogState {
-    fn default() -> Self {
-        LogState::Inactive
-    }
-}
-
 #[derive(Default)]
 pub struct StatusState {
     pub content: String,
     pub is_error: bool,
 }
 
+/// What the other computers see this one as.
+pub fn display_name(app: &App) -> &str {
+    if !app.config.name.is_empty() {
+        &app.config.name
+    } else {
+        &app.lan.local_name
+    }
+}
+
 pub fn set_status(app: &mut App, is_error: bool, message: impl AsRef<str>) {
     app.status.content.clear();
     app.status.content.push_str(message.as_ref());
@@ -65,8 +163,23 @@ pub struct RecipientState {
     /// For remembering which peer to move onto if tabbing away from a missing peer.
     pub index: usize,
     pub peer: Peer,
+    /// The entry for sending to all of the peers is selected. `valid` is false while it is.
+    pub everyone: bool,
+    /// Id of the group that's selected. `valid` is false while there is one.
+    pub group: Option<u64>,
 }
 
+impl RecipientState {
+    /// Whether there's anything to send a message to. A peer that's gone still counts since its
+    /// messages wait in the outbox.
+    pub fn is_chosen(&self) -> bool {
+        self.everyone || self.group.is_some() || !self.peer.name.is_empty()
+    }
+}
+
+/// Label of the entry for sending to all of the peers, at the top of the list.
+pub const EVERYONE: &str = "[everyone]";
+
 impl Default for RecipientState {
     fn default() -> Self {
         RecipientState {
@@ -74,8 +187,17 @@ impl Default for RecipientState {
             index: 0,
             peer: Peer {
                 name: Default::default(),
-                address: [0, 0, 0, 0].into(),
+                address: ([0, 0, 0, 0], 0).into(),
+                identity: None,
+                mismatch: false,
+                verified: false,
+                hostname: None,
+                rooms: vec![],
+                presence: None,
+                note: String::new(),
             },
+            everyone: false,
+            group: None,
         }
     }
 }
@@ -84,7 +206,10 @@ impl Default for RecipientState {
 pub struct LANState {
     pub peers: Vec<Peer>,
     pub local_name: String,
-    pub local_addr: String,
+    /// Fingerprint of this computer's key, in hex. Empty until the network thread loads it.
+    pub local_identity: String,
+    /// Every address that pings are currently being sent from.
+    pub local_addrs: Vec<IpAddr>,
 }
 
 pub struct LANIOState {
@@ -95,23 +220,49 @@ pub struct LANIOState {
 #[derive(Clone)]
 pub struct Peer {
     pub name: String,
-    pub address: IpAddr,
+    /// Where the peer receives messages.
+    pub address: SocketAddr,
+    /// Fingerprint of the key that the peer proved it has, in hex.
+    pub identity: Option<String>,
+    /// The identity doesn't match the one pinned to the name, or the name doesn't match the one
+    /// pinned to the identity.
+    pub mismatch: bool,
+    /// The user compared pairing codes with it.
+    pub verified: bool,
+    /// Sent by peers that picked a name of their own.
+    pub hostname: Option<String>,
+    /// The ones it's in, which aren't necessarily the same as this computer's.
+    pub rooms: Vec<String>,
+    /// None for peers that don't say.
+    pub presence: Option<Presence>,
+    pub note: String,
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, PartialEq, Eq, Default)]
 pub enum MessageType {
+    /// The peer's connection took it. As far as it gets with older builds.
+    #[default]
     Sent,
+    /// The peer stored it.
+    Delivered,
+    /// The user on the other side has seen it.
+    Read,
     Sending,
+    /// Waiting for the peer to come back.
+    Queued,
     SendFailed,
     Received,
     Error,
     Note,
 }
 
-impl Default for MessageType {
-    fn default() -> MessageType {
-        MessageType::Sent
-    }
+/// Whether a message was changed by its sender after it was sent.
+#[derive(Copy, Clone, PartialEq, Eq, Default)]
+pub enum Revision {
+    #[default]
+    Original,
+    Edited,
+    Retracted,
 }
 
 #[derive(Default)]
@@ -121,6 +272,23 @@ pub struct Message {
     pub name: String,
     pub content: String,
     pub message_id: u32,
+    /// The file transfer that the message shows, if it's one.
+    pub transfer: Option<u64>,
+    /// For telling the sender once the user has seen it. Taken when the receipt is sent.
+    pub receipt: Option<(SocketAddr, u64)>,
+    /// The sender and the id of the envelope for a received message, for finding it when it's
+    /// edited or retracted. None for peers too old to do either.
+    pub origin: Option<(SocketAddr, u64)>,
+    pub revision: Revision,
+}
+
+impl Message {
+    /// Sent by the user and not retracted, so it can still be edited or retracted.
+    pub fn is_changeable(&self) -> bool {
+        let sent = matches!(self.direction, MessageType::Sending | MessageType::Sent
+            | MessageType::Delivered | MessageType::Read | MessageType::Queued);
+        sent && self.transfer.is_none() && self.revision != Revision::Retracted
+    }
 }
 
 /// Must be called before the current process becomes multithreaded or else the `time` crate decides
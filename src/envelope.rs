@@ -0,0 +1,158 @@
+//! What copies of sclan send each other over their connections.
+//!
+//! ```text
+//! PREFIX | version | length | body
+//! ```
+//!
+//! The body is an `Envelope` encoded with bincode and the length is its size in bytes,
+//! big-endian. Builds from before envelopes send typed messages as bare UTF-8, which never starts
+//! with `PREFIX` because of the zero byte, so anything else is shown as text from one of them.
+//! They'd show envelopes as text too, so envelopes are only sent to computers that have shown they
+//! understand them, either with a fingerprint in their pings or by sending one first.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use bincode::{DefaultOptions, Options};
+use serde::{Deserialize, Serialize};
+use rand::random;
+use crate::control::Control;
+use crate::groups::Group;
+use crate::identity::Fingerprint;
+
+const PREFIX: &[u8] = b"\0SCLAN";
+/// Raised when a change to the envelope can't be read by older builds. Ones with a higher version
+/// are dropped.
+const VERSION: u8 = 1;
+const LENGTH_LEN: usize = 4;
+/// Bigger than anything sclan sends, which is an offer for a folder with a lot in it.
+/// Anything bigger is dropped.
+const MAX_BODY_LEN: u64 = 64 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize)]
+pub struct Envelope {
+    /// Random, for later messages to refer back to this one.
+    pub id: u64,
+    /// Milliseconds since the Unix epoch by the sender's clock.
+    pub sent_at: u64,
+    /// Of the sender's key. Only to be trusted from a peer that proved it has the key.
+    pub sender: Fingerprint,
+    pub kind: Kind,
+}
+
+/// New kinds go at the end so that the ones before keep their numbers on the wire.
+#[derive(Serialize, Deserialize)]
+pub enum Kind {
+    /// Typed by the user.
+    Text(String),
+    Control(Control),
+    /// About a text that was received.
+    Receipt {
+        /// Of the text's envelope.
+        id: u64,
+        /// false = it was stored and will be shown, true = the user has seen it
+        read: bool,
+    },
+    /// Typed by the user for a group. Carries the whole group so that every member ends up with
+    /// the latest one.
+    GroupText {
+        group: Group,
+        content: String,
+    },
+    /// A group was made or changed. Also sent to members that were taken out of it.
+    Group(Group),
+    /// The user is typing something for the receiver. Sent again every few seconds while that
+    /// goes on and never cleared, so it counts for a short while after each one.
+    Typing,
+    /// Replaces a text that was sent to the receiver. Older texts can't be changed anymore because
+    /// the sender only remembers where its latest ones went.
+    Edit {
+        /// Of the text's envelope.
+        id: u64,
+        content: String,
+    },
+    /// Takes back a text that was sent to the receiver.
+    Retract {
+        /// Of the text's envelope.
+        id: u64,
+    },
+}
+
+pub fn is_envelope(message: &[u8]) -> bool {
+    message.starts_with(PREFIX)
+}
+
+/// Wraps the kind in a new envelope, ready to send.
+pub fn seal(sender: Fingerprint, kind: Kind) -> Vec<u8> {
+    seal_with_id(random(), sender, kind)
+}
+
+/// For envelopes that need to be matched up with the answers to them.
+pub fn seal_with_id(id: u64, sender: Fingerprint, kind: Kind) -> Vec<u8> {
+    let sent_at = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|a| a.as_millis() as u64)
+        .unwrap_or(0);
+    let envelope = Envelope {id, sent_at, sender, kind};
+    // Can't fail without a limit.
+    let body = DefaultOptions::new().serialize(&envelope).unwrap();
+
+    let mut message = PREFIX.to_vec();
+    message.push(VERSION);
+    message.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    message.extend_from_slice(&body);
+    message
+}
+
+/// None for malformed envelopes and ones from newer versions.
+pub fn open(message: &[u8]) -> Option<Envelope> {
+    let (&version, rest) = message.strip_prefix(PREFIX)?.split_first()?;
+    if version != VERSION || rest.len() < LENGTH_LEN {
+        return None;
+    }
+    let (length, body) = rest.split_at(LENGTH_LEN);
+    if u32::from_be_bytes(length.try_into().unwrap()) as usize != body.len() {
+        return None;
+    }
+    // The limit keeps a bad length inside of the body from running out of memory.
+    DefaultOptions::new().with_limit(MAX_BODY_LEN).deserialize(body).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let message = seal_with_id(5, [1; 32], Kind::Text("hi".into()));
+
+        assert!(is_envelope(&message));
+        let envelope = open(&message).unwrap();
+        assert_eq!(envelope.id, 5);
+        assert_eq!(envelope.sender, [1; 32]);
+        assert!(matches!(envelope.kind, Kind::Text(a) if a == "hi"));
+    }
+
+    #[test]
+    fn wrong_version() {
+        let mut message = seal([1; 32], Kind::Typing);
+        message[PREFIX.len()] = VERSION + 1;
+
+        assert!(open(&message).is_none());
+    }
+
+    #[test]
+    fn length_mismatch() {
+        let message = seal([1; 32], Kind::Text("hi".into()));
+
+        assert!(open(&message[..message.len() - 1]).is_none());
+        let mut longer = message.clone();
+        longer.push(0);
+        assert!(open(&longer).is_none());
+        assert!(open(&message[..PREFIX.len() + 1 + LENGTH_LEN - 1]).is_none());
+    }
+
+    #[test]
+    fn legacy_text() {
+        assert!(!is_envelope(b"hello"));
+        assert!(!is_envelope(b"SCLAN"));
+        assert!(open(b"hello").is_none());
+    }
+}
@@ -0,0 +1,78 @@
+use std::fs::{read_to_string, write};
+use std::io::ErrorKind;
+use serde::{Deserialize, Serialize};
+use crate::identity::Fingerprint;
+
+const GROUPS_DEST: &str = "./sclan.groups";
+
+/// Most computers in a group, counting this one.
+pub const MAX_MEMBERS: usize = 50;
+pub const MAX_GROUP_NAME_LEN: usize = 255;
+const FINGERPRINT_LEN: usize = std::mem::size_of::<Fingerprint>();
+
+/// A set of peers that messages can be sent to together. Every member has its own copy, which is
+/// replaced whenever another member sends a newer one.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Group {
+    /// Picked by the computer that made the group.
+    pub id: u64,
+    pub name: String,
+    /// Fingerprints in hex, including this computer's. Only peers with proven identities can be in
+    /// a group, so that another computer can't pass for a member.
+    pub members: Vec<String>,
+}
+
+impl Group {
+    /// Whether a group that came from another computer is sane, including that it can be saved
+    /// without breaking up the line that it goes on.
+    pub fn is_valid(&self) -> bool {
+        !self.name.trim().is_empty() && self.name.len() <= MAX_GROUP_NAME_LEN
+            && !self.name.chars().any(|a| a.is_control())
+            && !self.members.is_empty() && self.members.len() <= MAX_MEMBERS
+            && self.members.iter().all(|a| is_fingerprint(a))
+    }
+}
+
+/// Lowercase hex of the right length, the way fingerprints are written everywhere else.
+fn is_fingerprint(text: &str) -> bool {
+    text.len() == FINGERPRINT_LEN * 2
+        && text.chars().all(|a| a.is_ascii_digit() || ('a'..='f').contains(&a))
+}
+
+/// One `id members name` per line, where members is a comma-separated list of fingerprints.
+pub fn load_groups() -> Result<Vec<Group>, String> {
+    let text = match read_to_string(GROUPS_DEST) {
+        Ok(a) => a,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(error) => return Err(format!("{}: {}", GROUPS_DEST, error)),
+    };
+
+    let mut groups = vec![];
+    for (number, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut parts = line.splitn(3, ' ');
+        let (id, members, name) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(a), Some(b), Some(c)) => (a, b, c),
+            _ => return Err(format!("{} line {}: expected `id members name`",
+                GROUPS_DEST, number + 1)),
+        };
+        let id = u64::from_str_radix(id, 16)
+            .map_err(|_| format!("{} line {}: invalid id `{}`", GROUPS_DEST, number + 1, id))?;
+        groups.push(Group {
+            id,
+            name: name.to_string(),
+            members: members.split(',').map(|a| a.to_string()).collect(),
+        });
+    }
+    Ok(groups)
+}
+
+pub fn save_groups(groups: &[Group]) -> Result<(), String> {
+    let mut text = String::new();
+    for group in groups {
+        text.push_str(&format!("{:016x} {} {}\n", group.id, group.members.join(","), group.name));
+    }
+    write(GROUPS_DEST, text).map_err(|a| format!("{}: {}", GROUPS_DEST, a))
+}
@@ -0,0 +1,140 @@
+use std::fs::{read, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::str::from_utf8;
+use std::net::{IpAddr, SocketAddr};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use ring::digest::{digest, SHA256};
+
+const KEY_DEST: &str = "./sclan.key";
+
+/// Signed along with the challenge so that a proof can't be mistaken for any other signature.
+const PROOF_CONTEXT: &[u8] = b"sclan identity proof";
+const PAIRING_CONTEXT: &[u8] = b"sclan pairing code";
+
+/// SHA-256 of a public key.
+pub type Fingerprint = [u8; 32];
+
+/// The long-term key pair that other computers recognize this one by.
+pub struct Identity {
+    key: Ed25519KeyPair,
+    pub fingerprint: Fingerprint,
+}
+
+impl Identity {
+    fn new(key: Ed25519KeyPair) -> Identity {
+        let fingerprint = fingerprint(key.public_key().as_ref());
+        Identity {key, fingerprint}
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        self.key.public_key().as_ref()
+    }
+
+    /// Signs a challenge from another computer to show that this one has the private key. The
+    /// addresses tie the proof to the connection that it's sent over.
+    pub fn prove(&self, challenge: &[u8], verifier: SocketAddr, prover: SocketAddr) -> Vec<u8> {
+        self.key.sign(&proof_message(challenge, verifier, prover)).as_ref().to_vec()
+    }
+}
+
+/// Reads the key pair, generating and saving a new one the first time.
+pub fn load_identity() -> Result<Identity, String> {
+    match read(KEY_DEST) {
+        Ok(bytes) => {
+            let key = Ed25519KeyPair::from_pkcs8(&bytes)
+                .map_err(|a| format!("{}: {}", KEY_DEST, a))?;
+            return Ok(Identity::new(key));
+        }
+        Err(error) if error.kind() == ErrorKind::NotFound => {}
+        Err(error) => return Err(format!("{}: {}", KEY_DEST, error)),
+    }
+
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .map_err(|_| "could not generate a key".to_string())?;
+    save_key(pkcs8.as_ref()).map_err(|a| format!("{}: {}", KEY_DEST, a))?;
+    let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).map_err(|a| a.to_string())?;
+    Ok(Identity::new(key))
+}
+
+/// For when the key file can't be used. Other computers will see a new identity next time.
+pub fn temporary_identity() -> Identity {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .expect("key generation failed");
+    Identity::new(Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).expect("generated key rejected"))
+}
+
+fn save_key(pkcs8: &[u8]) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(KEY_DEST)?;
+    file.write_all(pkcs8)?;
+    file.sync_all()
+}
+
+pub fn fingerprint(public_key: &[u8]) -> Fingerprint {
+    digest(&SHA256, public_key).as_ref().try_into().unwrap()
+}
+
+pub fn check_proof(public_key: &[u8], challenge: &[u8], verifier: SocketAddr, prover: SocketAddr,
+        signature: &[u8]) -> bool {
+    let message = proof_message(challenge, verifier, prover);
+    UnparsedPublicKey::new(&ED25519, public_key).verify(&message, signature).is_ok()
+}
+
+/// Leaves out the IPv6 scope, which is different on each end.
+fn proof_message(challenge: &[u8], verifier: SocketAddr, prover: SocketAddr) -> Vec<u8> {
+    let mut message = PROOF_CONTEXT.to_vec();
+    message.extend_from_slice(challenge);
+    for address in [verifier, prover] {
+        match address.ip() {
+            IpAddr::V4(ip) => message.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => message.extend_from_slice(&ip.octets()),
+        }
+        message.extend_from_slice(&address.port().to_be_bytes());
+    }
+    message
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|a| format!("{:02x}", a)).collect()
+}
+
+pub fn from_hex(text: &str) -> Option<Vec<u8>> {
+    text.as_bytes().chunks(2)
+        .map(|a| from_utf8(a).ok()
+            .filter(|a| a.len() == 2)
+            .and_then(|a| u8::from_str_radix(a, 16).ok()))
+        .collect()
+}
+
+/// Twenty digits made from the fingerprints of two computers. It comes out the same on both of
+/// them, so when the users see the same code each computer has the other's real key. Any shorter
+/// and a computer in the middle could try keys until one gives the same code.
+pub fn pairing_code(a: &str, b: &str) -> String {
+    let (first, second) = if a < b { (a, b) } else { (b, a) };
+    let mut input = PAIRING_CONTEXT.to_vec();
+    input.extend_from_slice(first.as_bytes());
+    input.extend_from_slice(second.as_bytes());
+    let hash = digest(&SHA256, &input);
+    let number = u128::from_be_bytes(hash.as_ref()[..16].try_into().unwrap()) % 10u128.pow(20);
+    let digits = format!("{:020}", number).chars().collect::<Vec<_>>();
+    digits.chunks(5)
+        .map(|a| a.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The start of a fingerprint in groups of four, short enough for people to compare.
+pub fn short_fingerprint(fingerprint: &str) -> String {
+    let chars = fingerprint.chars().take(16).collect::<Vec<_>>();
+    chars.chunks(4)
+        .map(|a| a.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
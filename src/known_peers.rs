@@ -0,0 +1,217 @@
+use std::fs::{read_to_string, write};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+
+const KNOWN_PEERS_DEST: &str = "./sclan.peers";
+
+/// Starts the keys of peers that are too old to have a fingerprint.
+const IP_PREFIX: &str = "ip:";
+
+/// The name that a key was first seen with, to catch other computers pretending to be this one
+/// later on, and what the user decided about it.
+pub struct KnownPeer {
+    /// In hex. Builds from before identities are recorded by `IP_PREFIX` and their IP instead,
+    /// which is only good for blocking and muting.
+    pub fingerprint: String,
+    pub name: String,
+    /// The user compared pairing codes with it.
+    pub verified: bool,
+    /// The user chose to always accept its messages.
+    pub approved: bool,
+    /// Hidden and its messages dropped.
+    pub blocked: bool,
+    /// Its messages are shown but don't count as unread.
+    pub muted: bool,
+}
+
+impl KnownPeer {
+    /// Whether the entry holds an actual fingerprint.
+    pub fn is_pin(&self) -> bool {
+        !self.fingerprint.starts_with(IP_PREFIX)
+    }
+}
+
+pub enum Pin {
+    /// First time seeing the key. It's now pinned to the name.
+    New,
+    Same,
+    /// The key was pinned to a different name.
+    Renamed(String),
+    /// The name was pinned to a different key.
+    Changed,
+}
+
+pub fn load_known_peers() -> Result<Vec<KnownPeer>, String> {
+    match read_to_string(KNOWN_PEERS_DEST) {
+        Ok(text) => parse_known_peers(&text),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(vec![]),
+        Err(error) => Err(format!("{}: {}", KNOWN_PEERS_DEST, error)),
+    }
+}
+
+pub fn save_known_peers(peers: &[KnownPeer]) -> Result<(), String> {
+    write(KNOWN_PEERS_DEST, format_known_peers(peers))
+        .map_err(|a| format!("{}: {}", KNOWN_PEERS_DEST, a))
+}
+
+/// One `fingerprint flags name` per line, where flags is a comma-separated list or `-` for none.
+/// Names come from other computers, so line breaks in them are escaped.
+fn parse_known_peers(text: &str) -> Result<Vec<KnownPeer>, String> {
+    let mut peers = vec![];
+    for (number, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut parts = line.splitn(3, ' ');
+        let (fingerprint, flags, name) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(a), Some(b), Some(c)) => (a, b, c),
+            _ => return Err(format!("{} line {}: expected `fingerprint flags name`",
+                KNOWN_PEERS_DEST, number + 1)),
+        };
+        let flags = flags.split(',').collect::<Vec<_>>();
+        peers.push(KnownPeer {
+            fingerprint: fingerprint.to_string(),
+            name: unescape(name),
+            verified: flags.contains(&"verified"),
+            approved: flags.contains(&"approved"),
+            blocked: flags.contains(&"blocked"),
+            muted: flags.contains(&"muted"),
+        });
+    }
+    Ok(peers)
+}
+
+fn format_known_peers(peers: &[KnownPeer]) -> String {
+    let mut text = String::new();
+    for peer in peers {
+        let mut flags = vec![];
+        if peer.verified {
+            flags.push("verified");
+        }
+        if peer.approved {
+            flags.push("approved");
+        }
+        if peer.blocked {
+            flags.push("blocked");
+        }
+        if peer.muted {
+            flags.push("muted");
+        }
+        if flags.is_empty() {
+            flags.push("-");
+        }
+        let name = escape(&peer.name);
+        text.push_str(&format!("{} {} {}\n", peer.fingerprint, flags.join(","), name));
+    }
+    text
+}
+
+fn escape(name: &str) -> String {
+    name.replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+fn unescape(name: &str) -> String {
+    let mut result = String::new();
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some(other) => result.push(other),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+/// Pins the key to the name if neither has been seen before.
+pub fn check_pin(peers: &mut Vec<KnownPeer>, fingerprint: &str, name: &str) -> Pin {
+    if let Some(known) = peers.iter().find(|a| a.fingerprint == fingerprint) {
+        if known.name == name {
+            return Pin::Same;
+        }
+        return Pin::Renamed(known.name.clone());
+    }
+    if peers.iter().any(|a| a.is_pin() && a.name == name) {
+        return Pin::Changed;
+    }
+
+    peers.push(new_known_peer(fingerprint, name));
+    Pin::New
+}
+
+fn new_known_peer(key: &str, name: &str) -> KnownPeer {
+    KnownPeer {
+        fingerprint: key.to_string(),
+        name: name.to_string(),
+        verified: false,
+        approved: false,
+        blocked: false,
+        muted: false,
+    }
+}
+
+/// What a peer is recorded by: its fingerprint, or its IP if it doesn't have one.
+pub fn peer_key(identity: Option<&str>, address: SocketAddr) -> String {
+    match identity {
+        Some(a) => a.to_string(),
+        None => format!("{}{}", IP_PREFIX, address.ip()),
+    }
+}
+
+/// Adds an entry for the key if there isn't one.
+pub fn known_peer_mut<'a>(peers: &'a mut Vec<KnownPeer>, key: &str, name: &str)
+        -> &'a mut KnownPeer {
+    match peers.iter().position(|a| a.fingerprint == key) {
+        Some(index) => &mut peers[index],
+        None => {
+            peers.push(new_known_peer(key, name));
+            peers.last_mut().unwrap()
+        }
+    }
+}
+
+/// Checks the IP as well as the fingerprint so that blocking a peer from before identities keeps
+/// working after it's updated.
+pub fn find_flag(peers: &[KnownPeer], identity: Option<&str>, address: SocketAddr,
+        flag: fn(&KnownPeer) -> bool) -> bool {
+    let ip_key = peer_key(None, address);
+    peers.iter()
+        .filter(|a| Some(a.fingerprint.as_str()) == identity || a.fingerprint == ip_key)
+        .any(flag)
+}
+
+pub fn is_verified(peers: &[KnownPeer], fingerprint: &str, name: &str) -> bool {
+    peers.iter().any(|a| a.fingerprint == fingerprint && a.name == name && a.verified)
+}
+
+/// Pins the key to the name as verified. Unverified pins of the name are dropped since the user
+/// now knows which key belongs to it.
+pub fn pin_verified(peers: &mut Vec<KnownPeer>, fingerprint: &str, name: &str) {
+    peers.retain(|a| a.fingerprint == fingerprint || !a.is_pin() || a.name != name || a.verified);
+    let known = known_peer_mut(peers, fingerprint, name);
+    known.name = name.to_string();
+    known.verified = true;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_with_line_break_stays_one_entry() {
+        let name = "x\nabcd verified,approved alice\\n";
+        let peers = vec![new_known_peer("1234", name)];
+
+        let loaded = parse_known_peers(&format_known_peers(&peers)).unwrap();
+
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].fingerprint, "1234");
+        assert_eq!(loaded[0].name, name);
+        assert!(!loaded[0].verified && !loaded[0].approved);
+    }
+}
@@ -2,8 +2,9 @@ use tui::backend::Backend;
 use tui::layout::{Constraint, Direction, Layout, Alignment, Rect};
 use tui::Frame;
 use crate::App;
+use crate::data::{Peer, EVERYONE};
 use crate::render::{ui_scrolling_list, render_input, ui_instructions, ui_info, ui_messages,
-                    ui_status};
+                    ui_status, info_height, presence_label};
 
 struct Cells {
     cell_info: Rect,
@@ -14,7 +15,7 @@ struct Cells {
     cell_status: Rect,
 }
 
-fn calc_layout(base: Rect) -> Cells {
+fn calc_layout(base: Rect, info_height: u16) -> Cells {
     let horiz = Layout::default()
         .direction(Direction::Horizontal)
         .vertical_margin(1)
@@ -27,9 +28,9 @@ fn calc_layout(base: Rect) -> Cells {
 
     let side = Layout::default()
         .constraints([
-            Constraint::Length(7),
+            Constraint::Length(info_height),
             Constraint::Min(3),
-            Constraint::Length(10),
+            Constraint::Length(11),
         ])
         .split(horiz[1]);
 
@@ -54,20 +55,64 @@ fn calc_layout(base: Rect) -> Cells {
     Cells {cell_info, cell_peers, cell_instructions, cell_input, cell_messages, cell_status}
 }
 
+/// Copies of sclan on the same machine have the same name so the port tells them apart.
+/// The rooms shown are the ones that the peer shares with this computer.
+fn peer_label(peer: &Peer, peers: &[Peer], rooms: &[String]) -> String {
+    let mut label = if peers.iter().filter(|a| a.name == peer.name).count() > 1 {
+        format!("{}:{}", peer.name, peer.address.port())
+    } else {
+        peer.name.clone()
+    };
+    for room in peer.rooms.iter().filter(|a| rooms.contains(a)) {
+        label.push_str(&format!(" #{}", room));
+    }
+    label.push_str(&presence_label(peer.presence, &peer.note));
+    if peer.mismatch {
+        format!("⚠ {}", label)
+    } else if peer.verified {
+        format!("✓ {}", label)
+    } else {
+        label
+    }
+}
+
 pub fn ui<B: Backend>(frame: &mut Frame<B>, app: &App) {
     let Cells {cell_info, cell_peers, cell_instructions, cell_input, cell_messages, cell_status} =
-        calc_layout(frame.size());
+        calc_layout(frame.size(), info_height(app));
 
     frame.render_widget(ui_info(app).alignment(Alignment::Right), cell_info);
 
-    let options = app.lan.peers.iter().map(|peer| peer.name.clone()).collect::<Vec<_>>();
+    let mut options = app.lan.peers.iter()
+        .map(|peer| peer_label(peer, &app.lan.peers, &app.config.rooms)).collect::<Vec<_>>();
+    let groups = app.groups.iter().map(|a| format!("[{}]", a.name));
+    options.splice(0..0, groups);
+    if !options.is_empty() {
+        options.insert(0, EVERYONE.into());
+    }
+    // The copy in the recipient doesn't get updated when the peer's marks change.
+    let recipient = app.lan.peers.iter()
+        .find(|a| a.address == app.recipient.peer.address)
+        .unwrap_or(&app.recipient.peer);
+    let group = app.recipient.group.and_then(|id| app.groups.iter().find(|a| a.id == id));
+    let selection = if app.recipient.everyone {
+        EVERYONE.into()
+    } else if let Some(group) = group {
+        format!("[{}]", group.name)
+    } else {
+        peer_label(recipient, &app.lan.peers, &app.config.rooms)
+    };
     frame.render_widget(ui_scrolling_list(
-        cell_peers, "network:", &app.recipient.peer.name, &options
+        cell_peers, "network:", &selection, &options
     ).alignment(Alignment::Right), cell_peers);
 
+    let sent_selected = app.message_highlight
+        .and_then(|i| app.messages.get(i as usize))
+        .is_some_and(|a| a.is_changeable());
+    // A message being edited goes to the peers that got it, whoever is selected.
+    let recipient_chosen = app.recipient.is_chosen() || app.editing.is_some();
     frame.render_widget(ui_instructions(
-        app.input_mode, app.recipient.valid, app.input.trim().len() > 0, app.messages.len() > 0,
-        app.message_highlight.is_some(), app.logging
+        app.input_mode, recipient_chosen, !app.input.trim().is_empty(),
+        !app.messages.is_empty(), app.message_highlight.is_some(), sent_selected, app.logging
     ), cell_instructions);
 
     frame.render_widget(ui_status(app), cell_status);
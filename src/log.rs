@@ -32,11 +32,7 @@ pub async fn task_log(mut to_app: Sender<FromNet>, mut messages: MReceiver<ToLog
     let mut ofile = open_file(false).await.ok();
     loop {
         match (prev, ofile.is_some()) {
-            (true, false) => {
-                if let Err(_) = to_app.send(FromNet::LogStopped) {
-                    return;
-                }
-            }
+            (true, false) if to_app.send(FromNet::LogStopped).is_err() => return,
             (false, true) => {
                 // Must be after file is opened because canonicalize won't work on a file that
                 // doesn't exit yet.
@@ -45,7 +41,7 @@ pub async fn task_log(mut to_app: Sender<FromNet>, mut messages: MReceiver<ToLog
                     Err(_) => LOG_DEST.into(),
                 };
                 
-                if let Err(_) = to_app.send(FromNet::LogStarted(abs_log)) {
+                if to_app.send(FromNet::LogStarted(abs_log)).is_err() {
                     return;
                 }
             }
@@ -66,7 +62,7 @@ pub async fn task_log(mut to_app: Sender<FromNet>, mut messages: MReceiver<ToLog
                     }
                     Err(error) => {
                         // Cancels the Pending state.
-                        if let Err(_) = to_app.send(FromNet::LogStopped) {
+                        if to_app.send(FromNet::LogStopped).is_err() {
                             return;
                         }
                         if !show_error(to_app, format!("error: {:?}", error)) {
@@ -2,25 +2,72 @@ mod data;
 mod network;
 mod network_broadcast;
 mod network_p2p;
+mod network_files;
+#[cfg(feature = "mdns")]
+mod network_mdns;
 mod render;
 mod layout;
 mod actions;
 mod log;
+mod config;
+mod commands;
+mod ping;
+mod identity;
+mod control;
+mod envelope;
+mod known_peers;
+mod groups;
 
+use std::env::args;
 use std::error::Error;
-use std::io::stdout;
+use std::io::{stdout, Write};
+use std::process::exit;
 use std::time::Duration;
 use crossterm::{
     event::{DisableMouseCapture, EnableMouseCapture},
     execute,
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+               SetTitle},
 };
 use tui::{backend::{Backend, CrosstermBackend}, Terminal};
 use crate::data::{App, InputMode, load_offset};
 use crate::layout::ui;
-use crate::actions::{input_async, input_terminal};
+use crate::actions::{input_async, input_terminal, check_idle, expire_typing,
+                     retry_queued};
+use crate::config::{load_config, USAGE};
+use crate::known_peers::load_known_peers;
+use crate::groups::load_groups;
+use crate::network::leave_network;
 
 fn main() -> Result<(), Box<dyn Error>> {
+    if args().any(|a| a == "--help" || a == "-h") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    // Read before the terminal is taken over so that problems can be printed normally.
+    let config = match load_config() {
+        Ok(a) => a,
+        Err(error) => {
+            eprintln!("{}\n\n{}", error, USAGE);
+            exit(2);
+        }
+    };
+
+    let known_peers = match load_known_peers() {
+        Ok(a) => a,
+        Err(error) => {
+            eprintln!("{}", error);
+            exit(2);
+        }
+    };
+    let groups = match load_groups() {
+        Ok(a) => a,
+        Err(error) => {
+            eprintln!("{}", error);
+            exit(2);
+        }
+    };
+
     // set up terminal
     enable_raw_mode()?;
     let mut stdout = stdout();
@@ -28,7 +75,12 @@ fn main() -> Result<(), Box<dyn Error>> {
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-    let mut app = App::default();
+    let mut app = App {
+        config,
+        known_peers,
+        groups,
+        ..Default::default()
+    };
     load_offset(&mut app);
     let res = run_app(&mut terminal, app);
 
@@ -48,12 +100,28 @@ fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> Result<(), Box<dyn Error>> {
+fn run_app<B: Backend + Write>(terminal: &mut Terminal<B>, mut app: App)
+        -> Result<(), Box<dyn Error>> {
     let app = &mut app;
     app.needs_redraw = true;
 
+    let mut title_unread = None;
     loop {
         input_async(app);
+        check_idle(app);
+        expire_typing(app);
+        retry_queued(app);
+
+        // Shows up in the taskbar or tab so that new messages get noticed from other windows.
+        if title_unread != Some(app.unread) {
+            title_unread = Some(app.unread);
+            let title = if app.unread > 0 {
+                format!("({}) sclan", app.unread)
+            } else {
+                "sclan".to_string()
+            };
+            execute!(terminal.backend_mut(), SetTitle(title))?;
+        }
 
         if app.needs_redraw {
             app.needs_redraw = false;
@@ -70,6 +138,7 @@ fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> Result<(), B
         input_terminal(app, Duration::from_millis(500))?;
 
         if app.quitting {
+            leave_network(app);
             return Ok(());
         }
     }
@@ -1,50 +1,269 @@
-use std::net::IpAddr;
-use std::time::Duration;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use std::sync::mpsc::{Sender, Receiver, channel, TryRecvError};
 use std::thread::Builder as ThreadBuilder;
 use gethostname::gethostname;
+use rand::random;
 use tokio::runtime::Builder as RuntimeBuilder;
-use tokio::time::sleep;
+use tokio::time::{sleep, timeout};
 use tokio::spawn;
 use tokio::sync::watch::channel as wchannel;
 use tokio::sync::mpsc::channel as tchannel;
 use crate::data::{App, LANIOState};
-use crate::network_broadcast::task_ping;
-use crate::network_p2p::task_p2p;
+use crate::network_broadcast::{task_ping, Advert, Profile};
+use crate::ping::Presence;
+use crate::network_p2p::{task_p2p, Setup};
 use crate::log::task_log;
+use crate::config::Config;
+use crate::identity::{load_identity, temporary_identity, to_hex};
+use crate::groups::Group;
+
+/// Longest that closing waits for the other computers to be told.
+const LEAVE_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long the discovery tasks get to send their last pings after the connections are closed.
+const LEAVE_PING_TIME: Duration = Duration::from_secs(1);
+
+/// Discovery traffic that was thrown away. Counted instead of reported one at a time so that a
+/// misbehaving device can't flood the UI.
+#[derive(Default)]
+pub struct Rejected {
+    /// Packets on the discovery port that aren't pings.
+    pub invalid: AtomicU64,
+    /// From sources sending more pings than any copy of sclan would.
+    pub flooding: AtomicU64,
+    /// New peers turned away because too many are being tracked already.
+    pub too_many: AtomicU64,
+    /// Where the last rejected packet or peer came from.
+    pub last_source: Mutex<Option<IpAddr>>,
+}
+
+impl Rejected {
+    pub fn count(&self, counter: &AtomicU64, source: IpAddr) {
+        counter.fetch_add(1, Ordering::Relaxed);
+        *self.last_source.lock().unwrap() = Some(source);
+    }
+
+    fn snapshot(&self) -> RejectedCounts {
+        RejectedCounts {
+            invalid: self.invalid.load(Ordering::Relaxed),
+            flooding: self.flooding.load(Ordering::Relaxed),
+            too_many: self.too_many.load(Ordering::Relaxed),
+            last_source: *self.last_source.lock().unwrap(),
+        }
+    }
+}
+
+/// See `Rejected`.
+#[derive(Copy, Clone, Default, PartialEq)]
+pub struct RejectedCounts {
+    pub invalid: u64,
+    pub flooding: u64,
+    pub too_many: u64,
+    pub last_source: Option<IpAddr>,
+}
 
 pub enum FromNet {
     ShowLocalName(String),
-    ShowLocalAddress(String),
+    /// Fingerprint of this computer's key, in hex.
+    ShowLocalIdentity(String),
+    /// Replaces the addresses of one IP version.
+    ShowLocalAddresses {
+        ipv6: bool,
+        addresses: Vec<IpAddr>,
+    },
     ShowStatus(String),
     ShowError(String),
     ShowMessage {
-        source: IpAddr,
+        source: SocketAddr,
         content: String,
+        /// For sending receipts back. None for peers too old to send them.
+        receipt: Option<u64>,
+        /// Sent to a group that this computer is in. Not checked against the UI's copy yet.
+        group: Option<Group>,
     },
+    /// A peer made or changed a group that has this computer in it, or took it out of one.
+    GroupShared {
+        source: SocketAddr,
+        group: Group,
+    },
+    /// The peer changed a message that it sent. None if it took the message back.
+    Edited {
+        source: SocketAddr,
+        /// Of the message's envelope.
+        id: u64,
+        content: Option<String>,
+    },
+    /// The peer is typing something for this computer. Comes again every few seconds while it is.
+    Typing(SocketAddr),
     SendFailed(u32),
+    /// The peer's connection took the message. Older builds never say anything more.
     SendArrived(u32),
+    /// The peer stored the message.
+    SendDelivered(u32),
+    /// The user on the other side has seen the message.
+    SendRead(u32),
+    /// How a message to all of the peers or to a group went.
+    BroadcastSent {
+        message_id: u32,
+        /// Number of peers whose connections took it.
+        sent: usize,
+        failed: usize,
+    },
+    /// Peers are identified by the address that they receive messages on. The IP alone isn't
+    /// enough because there can be more than one copy of sclan on a machine.
     Peer {
         name: String,
-        address: IpAddr,
+        address: SocketAddr,
+        /// Fingerprint of the key that the peer proved it has, in hex. None until it does.
+        identity: Option<String>,
+        /// Sent by peers that picked a name of their own.
+        hostname: Option<String>,
+        /// From its pings. Empty for peers that aren't in any or were added by hand.
+        rooms: Vec<String>,
+        /// None for peers that don't say.
+        presence: Option<Presence>,
+        note: String,
+    },
+    Peerbgone(SocketAddr),
+    /// Messages from a peer that isn't approved are being held. A count of 0 means they were
+    /// released or dropped.
+    Request {
+        address: SocketAddr,
+        count: usize,
     },
-    Peerbgone(IpAddr),
     LogStarted(String),
     LogStopped,
+    /// Totals since startup. Only sent when they change.
+    ShowRejected(RejectedCounts),
+    /// A peer wants to send a file or folder. The id is this computer's own, not the sender's.
+    FileOffer {
+        id: u64,
+        source: SocketAddr,
+        name: String,
+        /// Of all of the files together for a folder.
+        size: u64,
+        folder: bool,
+    },
+    /// A file or folder was offered to a peer. The UI doesn't know the size of a folder until then.
+    FileOffered {
+        id: u64,
+        size: u64,
+    },
+    /// Bytes sent or received so far. Not sent for every chunk.
+    FileProgress {
+        id: u64,
+        done: u64,
+    },
+    /// Where a received file was saved. None for files that were sent.
+    FileDone {
+        id: u64,
+        path: Option<String>,
+    },
+    FileFailed {
+        id: u64,
+        error: String,
+    },
 }
 
 pub enum ToNet {
     Send {
         message_id: u32,
-        address: IpAddr,
+        address: SocketAddr,
         content: String,
     },
+    /// Send to all of the peers at once.
+    Broadcast {
+        message_id: u32,
+        content: String,
+    },
+    /// Send to the members of the group at once.
+    SendGroup {
+        message_id: u32,
+        group: Group,
+        content: String,
+    },
+    /// Send the group to these members, for when it's made or changed.
+    ShareGroup {
+        group: Group,
+        /// Fingerprints in hex.
+        to: Vec<String>,
+    },
     LogStart,
+    /// Connect to `host` or `host:port` without waiting for a ping from it.
+    AddPeer(String),
+    SetPolicy(Policy),
+    /// Let the peer's held messages through. Later ones are held again.
+    Accept(SocketAddr),
+    /// Drop the peer's held messages.
+    Reject(SocketAddr),
+    /// Tell the other computers that this one is closing and stop.
+    Leave,
+    /// Replaces the rooms that peers have to be in to be found.
+    SetRooms(Vec<String>),
+    /// Name to show instead of the host name. Empty to go back to the host name.
+    SetName(String),
+    /// What the other computers are told about whether the user is around.
+    SetPresence {
+        presence: Presence,
+        note: String,
+    },
+    /// Offer a file or folder to a peer. The id is picked by the UI.
+    SendFile {
+        id: u64,
+        address: SocketAddr,
+        path: PathBuf,
+    },
+    /// Start receiving an offered file.
+    AcceptFile(u64),
+    /// Decline an offered file or stop a transfer in either direction.
+    CancelFile(u64),
+    /// Tell the peer that a message it sent was stored, or that the user has seen it.
+    Receipt {
+        address: SocketAddr,
+        id: u64,
+        read: bool,
+    },
+    /// Change a sent message for the peers that got it. None takes it back.
+    Edit {
+        message_id: u32,
+        content: Option<String>,
+    },
+    /// Tell the peer that the user is typing something for it. Skipped for peers too old to
+    /// understand it and ones that aren't connected, since it's only a hint.
+    Typing(SocketAddr),
+}
+
+/// Whose messages get through. Sent by the UI at startup and whenever it changes.
+#[derive(Clone, Default)]
+pub struct Policy {
+    /// Hold messages from everyone else for the user to accept or reject.
+    pub approved_only: bool,
+    /// Fingerprints in hex.
+    pub approved: Vec<String>,
+    /// Fingerprints in hex, or `ip:` and the IP for peers that don't have one.
+    pub blocked: Vec<String>,
+}
+
+pub fn current_policy(app: &App) -> Policy {
+    Policy {
+        approved_only: app.config.approved_only,
+        approved: app.known_peers.iter()
+            .filter(|a| a.approved || a.verified)
+            .map(|a| a.fingerprint.clone())
+            .collect(),
+        blocked: app.known_peers.iter()
+            .filter(|a| a.blocked)
+            .map(|a| a.fingerprint.clone())
+            .collect(),
+    }
 }
 
 pub fn message_to_net(app: &mut App, message: ToNet) -> Result<(), ()> {
     let state = app.lan_io.as_ref().ok_or(())?;
-    if let Err(_) = state.to_lan.send(message) {
+    if state.to_lan.send(message).is_err() {
         app.lan_io = None;
         return Err(());
     }
@@ -66,10 +285,28 @@ pub fn message_from_net(app: &mut App) -> Option<FromNet> {
     }
 }
 
+/// Tells the other computers that this one is closing. Gives up after `LEAVE_TIMEOUT` so that
+/// closing never hangs.
+pub fn leave_network(app: &mut App) {
+    let LANIOState {to_lan, from_lan} = match app.lan_io.take() {
+        Some(a) => a,
+        None => return,
+    };
+    if to_lan.send(ToNet::Leave).is_err() {
+        return;
+    }
+    // Lets the thread that passes commands along finish.
+    drop(to_lan);
+
+    // The network thread drops its end once it's done.
+    let deadline = Instant::now() + LEAVE_TIMEOUT;
+    while from_lan.recv_timeout(deadline.saturating_duration_since(Instant::now())).is_ok() {}
+}
+
 /// false = disconnected
 #[must_use]
 pub fn show_status(to_app: &mut Sender<FromNet>, content: impl Into<String>) -> bool {
-    if let Err(_) = to_app.send(FromNet::ShowStatus(content.into())) {
+    if to_app.send(FromNet::ShowStatus(content.into())).is_err() {
         return false;
     }
     true
@@ -78,21 +315,28 @@ pub fn show_status(to_app: &mut Sender<FromNet>, content: impl Into<String>) ->
 /// false = disconnected
 #[must_use]
 pub fn show_error(to_app: &mut Sender<FromNet>, content: impl Into<String>) -> bool {
-    if let Err(_) = to_app.send(FromNet::ShowError(content.into())) {
+    if to_app.send(FromNet::ShowError(content.into())).is_err() {
         return false;
     }
     true
 }
 
 fn start_network(app: &mut App) {
+    let config = app.config.clone();
     let (to_lan, from_app) = channel();
     let (mut to_app, from_lan) = channel();
+    // Goes first so that it's in place before any messages arrive.
+    let _ignore = to_lan.send(ToNet::SetPolicy(current_policy(app)));
+    let _ignore = to_lan.send(ToNet::SetPresence {
+        presence: app.presence,
+        note: app.note.clone(),
+    });
 
     let _ignore = show_status(&mut to_app, "starting thread");
     let mut to_app_2 = to_app.clone();
     if let Err(error) = ThreadBuilder::new()
             .name("async".into())
-            .spawn(move || run_network(from_app, to_app)) {
+            .spawn(move || run_network(config, from_app, to_app)) {
         let _ignore = show_error(&mut to_app_2, format!("error starting thread: {:?}", error));
         return;
     }
@@ -100,7 +344,7 @@ fn start_network(app: &mut App) {
     app.lan_io = Some(LANIOState {to_lan, from_lan});
 }
 
-fn run_network(from_app: Receiver<ToNet>, mut to_app: Sender<FromNet>) {
+fn run_network(config: Config, from_app: Receiver<ToNet>, mut to_app: Sender<FromNet>) {
     if !show_status(&mut to_app, "starting runtime") {
         return;
     }
@@ -109,7 +353,7 @@ fn run_network(from_app: Receiver<ToNet>, mut to_app: Sender<FromNet>) {
         .build();
     match runtime {
         Ok(runtime) => {
-            runtime.block_on(run_network_async(from_app, to_app));
+            runtime.block_on(run_network_async(config, from_app, to_app));
         }
         Err(error) => {
             let _ignore = show_error(&mut to_app, format!("error building runtime: {:?}", error));
@@ -117,32 +361,83 @@ fn run_network(from_app: Receiver<ToNet>, mut to_app: Sender<FromNet>) {
     }
 }
 
-async fn run_network_async(from_app: Receiver<ToNet>, mut to_app: Sender<FromNet>) {
+async fn run_network_async(config: Config, from_app: Receiver<ToNet>,
+        mut to_app: Sender<FromNet>) {
     if !show_status(&mut to_app, "runtime started") {
         return;
     }
 
     let (send_peer, receive_peer) = tchannel(1);
     let (send_port, watch_port) = wchannel(None);
+    let (send_profile, watch_profile) = wchannel(Profile {
+        name: config.name.clone(),
+        rooms: config.rooms.clone(),
+        ..Default::default()
+    });
     let (send_log, receive_log) = tchannel(1);
+    let rejected = Arc::new(Rejected::default());
 
-    let a = spawn(task_local_name(to_app.clone()));
-    let b = spawn(task_ping(to_app.clone(), watch_port, send_peer));
-    let c = spawn(task_p2p(from_app, to_app.clone(), send_log, send_port, receive_peer));
+    let identity = match load_identity() {
+        Ok(a) => a,
+        Err(error) => {
+            if !show_error(&mut to_app, format!("{} - using a temporary identity", error)) {
+                return;
+            }
+            temporary_identity()
+        }
+    };
+    if to_app.send(FromNet::ShowLocalIdentity(to_hex(&identity.fingerprint))).is_err() {
+        return;
+    }
+    let advert = Advert {
+        instance: random(),
+        fingerprint: identity.fingerprint,
+    };
+    let setup = Setup {
+        port: config.port,
+        manual: config.peers,
+        identity,
+        approved_only: config.approved_only,
+        profile: send_profile,
+        rejected: rejected.clone(),
+    };
+
+    spawn(task_local_name(to_app.clone()));
+    spawn(task_report_rejected(rejected.clone(), to_app.clone()));
+    let b = spawn(task_ping(config.interfaces, advert, config.mdns, watch_profile, rejected,
+        to_app.clone(), watch_port, send_peer));
+    let c = spawn(task_p2p(setup, from_app, to_app.clone(), send_log, send_port, receive_peer));
     let d = spawn(task_log(to_app, receive_log));
 
-    for r in [a, b, c, d] {
-        r.await.expect("task panicked");
-    }
+    // task_p2p only stops when sclan is closing. The others find out from it and announce that
+    // this computer is leaving. The rest of the tasks are dropped along with the runtime.
+    c.await.expect("task panicked");
+    let _ = timeout(LEAVE_PING_TIME, b).await;
+    let _ = timeout(LEAVE_PING_TIME, d).await;
 }
 
 async fn task_local_name(to_app: Sender<FromNet>) {
     // TODO: can the host name be changed at runtime or is this loop a waste of time?
     loop {
         let name = gethostname().into_string().unwrap_or("".into());
-        if let Err(_) = to_app.send(FromNet::ShowLocalName(name)) {
+        if to_app.send(FromNet::ShowLocalName(name)).is_err() {
             return;
         }
         sleep(Duration::from_secs(5)).await;
     }
 }
+
+async fn task_report_rejected(rejected: Arc<Rejected>, to_app: Sender<FromNet>) {
+    let mut reported = RejectedCounts::default();
+    loop {
+        sleep(Duration::from_secs(5)).await;
+        let counts = rejected.snapshot();
+        if counts == reported {
+            continue;
+        }
+        reported = counts;
+        if to_app.send(FromNet::ShowRejected(counts)).is_err() {
+            return;
+        }
+    }
+}
@@ -1,26 +1,136 @@
-use std::str::from_utf8;
-use std::cmp::min;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
 use std::sync::mpsc::Sender;
 use std::sync::Arc;
+use std::collections::HashMap;
 use std::time::Duration;
 use std::io::{Error as IOError, Result as IOResult, ErrorKind};
+use socket2::{Socket, Domain, Type, Protocol};
+use if_addrs::{get_if_addrs, IfAddr};
 use tokio::net::UdpSocket;
-use tokio::time::sleep;
-use tokio::select;
-use tokio::sync::watch::Receiver as WReceiver;
+use tokio::time::{sleep, Instant};
+use tokio::{select, join};
+use tokio::sync::watch::{Receiver as WReceiver, Sender as WSender, channel as wchannel};
 use tokio::sync::mpsc::Sender as TSender;
 use gethostname::gethostname;
-use crate::network::{show_status, show_error, FromNet};
+use crate::network::{show_status, show_error, FromNet, Rejected};
+use crate::network_p2p::Ports;
+use crate::ping::{Ping, Presence, encode_ping, parse_ping, shares_room};
+use crate::identity::Fingerprint;
+#[cfg(feature = "mdns")]
+use crate::network_mdns::task_mdns;
 
 const PORT: u16 = 31331;
-const PING_INTERVAL: Duration = Duration::from_secs(4);
+/// Link-local scope keeps IPv6 pings on the same segment that an IPv4 broadcast would reach.
+/// The group id is just the port number in hex.
+const MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x7a63);
+pub const PING_INTERVAL: Duration = Duration::from_secs(4);
+/// More pings than this from one IP per `PING_INTERVAL` are dropped. Each copy of sclan sends one
+/// per interval, so this leaves room for several on one machine.
+const MAX_PINGS_PER_SOURCE: u32 = 16;
+/// Sources beyond this many in one interval are dropped, to keep the rate limit from growing
+/// without bound when the source addresses are forged.
+const MAX_SOURCES: usize = 1024;
+/// Most machines without IPv6 will never get it, so don't keep nagging about it.
+const RETRY_V6: Duration = Duration::from_secs(60);
 
-pub async fn task_ping(mut to_app: Sender<FromNet>, wport: WReceiver<Option<u16>>,
-        to_p2p: TSender<(SocketAddr, String)>) {
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Family {
+    V4,
+    V6,
+}
+
+/// The parts of this copy's pings that the user can change while sclan is running.
+#[derive(Clone, Default, PartialEq)]
+pub struct Profile {
+    /// Picked by the user to show instead of the host name. Empty to use the host name.
+    pub name: String,
+    /// Only peers in one of these are shown.
+    pub rooms: Vec<String>,
+    pub presence: Presence,
+    /// Written by the user to go along with the presence, like "back at 3".
+    pub note: String,
+}
+
+/// Returns (name, host name if it's different)
+pub fn ping_names(profile: &Profile) -> (String, Option<String>) {
+    let hostname = gethostname().into_string().unwrap_or("???".into());
+    if profile.name.is_empty() || profile.name == hostname {
+        return (hostname, None);
+    }
+    (profile.name.clone(), Some(hostname))
+}
+
+/// What this copy of sclan says about itself in pings besides its name and port.
+#[derive(Copy, Clone)]
+pub struct Advert {
+    /// Random number picked at startup, to tell this copy's own pings apart from those of other
+    /// copies on the same machine.
+    pub instance: u64,
+    pub fingerprint: Fingerprint,
+}
+
+/// A network that this machine is on, found by enumerating the local interfaces.
+#[derive(Clone, PartialEq)]
+struct LocalNet {
+    /// This machine's address on the network.
+    ip: IpAddr,
+    netmask: IpAddr,
+    /// Where pings for this network get sent.
+    dest: SocketAddr,
+    /// Needed to tell IPv6 link-local networks apart since they all use the same prefix.
+    index: Option<u32>,
+}
+
+/// `interfaces` limits discovery to the named interfaces. Empty means all of them.
+/// `mdns` also advertises and browses over multicast DNS alongside the pings.
+/// `profile` is kept up to date by `task_p2p` when the user changes it.
+/// `rejected` counts the pings that are thrown away.
+#[allow(clippy::too_many_arguments)]
+pub async fn task_ping(interfaces: Vec<String>, advert: Advert, mdns: bool,
+        profile: WReceiver<Profile>, rejected: Arc<Rejected>, to_app: Sender<FromNet>,
+        wport: WReceiver<Option<Ports>>, to_p2p: TSender<(SocketAddr, Ping)>) {
+    join!(
+        task_ping_family(Family::V4, &interfaces, advert, profile.clone(), rejected.clone(),
+            to_app.clone(), wport.clone(), to_p2p.clone()),
+        task_ping_family(Family::V6, &interfaces, advert, profile.clone(), rejected,
+            to_app.clone(), wport.clone(), to_p2p.clone()),
+        task_discover_mdns(mdns, interfaces.clone(), advert, profile, to_app, wport, to_p2p),
+    );
+}
+
+#[cfg(feature = "mdns")]
+async fn task_discover_mdns(enabled: bool, interfaces: Vec<String>, advert: Advert,
+        profile: WReceiver<Profile>, to_app: Sender<FromNet>, wport: WReceiver<Option<Ports>>,
+        to_p2p: TSender<(SocketAddr, Ping)>) {
+    if enabled {
+        task_mdns(interfaces, advert, profile, to_app, wport, to_p2p).await;
+    }
+}
+
+#[cfg(not(feature = "mdns"))]
+async fn task_discover_mdns(enabled: bool, _interfaces: Vec<String>, _advert: Advert,
+        _profile: WReceiver<Profile>, mut to_app: Sender<FromNet>,
+        _wport: WReceiver<Option<Ports>>, _to_p2p: TSender<(SocketAddr, Ping)>) {
+    if enabled {
+        let _ignore = show_error(&mut to_app, "mDNS is not available in this build");
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn task_ping_family(family: Family, interfaces: &[String], advert: Advert,
+        profile: WReceiver<Profile>, rejected: Arc<Rejected>, mut to_app: Sender<FromNet>,
+        wport: WReceiver<Option<Ports>>, to_p2p: TSender<(SocketAddr, Ping)>) {
     loop {
-        let socket = match make_socket().await {
+        let socket = match make_socket(family).await {
             Err(error) => {
+                if family == Family::V6 {
+                    if !show_status(&mut to_app, format!("IPv6 unavailable: {:?}", error.kind())) {
+                        return;
+                    }
+                    sleep(RETRY_V6).await;
+                    continue;
+                }
+
                 if error.kind() == ErrorKind::AddrInUse {
                     if !show_error(&mut to_app, "error: address already in use") {
                         return;
@@ -38,13 +148,15 @@ pub async fn task_ping(mut to_app: Sender<FromNet>, wport: WReceiver<Option<u16>
         };
         let socket = Arc::new(socket);
 
-        if !show_status(&mut to_app, "connected") {
+        if family == Family::V4 && !show_status(&mut to_app, "connected") {
             return;
         }
-        show_local_ip(&mut to_app);
 
-        let pout = task_ping_out(socket.clone(), to_app.clone(), wport.clone());
-        let pin = task_ping_in(socket, to_app.clone(), to_p2p.clone());
+        let (send_nets, watch_nets) = wchannel(vec![]);
+        let pout = task_ping_out(family, interfaces, advert, profile.clone(), socket.clone(),
+            to_app.clone(), wport.clone(), send_nets);
+        let pin = task_ping_in(!interfaces.is_empty(), advert, profile.clone(), socket,
+            &rejected, to_p2p.clone(), watch_nets);
 
         let done = select! {
             a = pout => a,
@@ -59,7 +171,7 @@ pub async fn task_ping(mut to_app: Sender<FromNet>, wport: WReceiver<Option<u16>
             }
         }
 
-        if let Err(_) = to_app.send(FromNet::ShowLocalAddress("".into())) {
+        if !show_local_nets(&mut to_app, family, &[]) {
             return;
         }
 
@@ -72,135 +184,302 @@ enum PingDone {
     IO(IOError),
 }
 
-async fn task_ping_in(socket: Arc<UdpSocket>, mut to_app: Sender<FromNet>,
-        to_p2p: TSender<(SocketAddr, String)>) -> PingDone {
-    let to_app = &mut to_app;
+/// `filtered` = only accept pings arriving from the networks that are being pinged
+/// Pings from peers that aren't in any of the same rooms are ignored.
+async fn task_ping_in(filtered: bool, advert: Advert, profile: WReceiver<Profile>,
+        socket: Arc<UdpSocket>, rejected: &Rejected, to_p2p: TSender<(SocketAddr, Ping)>,
+        nets: WReceiver<Vec<LocalNet>>) -> PingDone {
+    let mut limit = RateLimit {started: Instant::now(), counts: HashMap::new()};
     let mut buf = [0; 2048];
     loop {
         let (count, source) = match socket.recv_from(&mut buf).await {
             Ok(a) => a,
             Err(error) => return PingDone::IO(error),
         };
-        let ip = source.ip();
-        if ip == IpAddr::from([127, 0, 0, 1]) {
+        if filtered && !from_local_net(&source, &nets.borrow()) {
+            continue;
+        }
+        if !limit.allows(source.ip()) {
+            rejected.count(&rejected.flooding, source.ip());
             continue;
         }
 
         let message = &buf[..count];
 
-        let (name, port) = if let Some(a) = parse_ping(message) {
+        let ping = if let Some(a) = parse_ping(message) {
             a
         } else {
-            if !show_status(to_app, format!("invalid ping from {:?}", source)) {
-                return PingDone::Exiting;
-            }
+            rejected.count(&rejected.invalid, source.ip());
             continue;
         };
 
-        let peer = FromNet::Peer {
-            name: name.to_string(),
-            address: ip,
-        };
-        if let Err(_) = to_app.send(peer) {
-            return PingDone::Exiting;
+        if ping.instance == Some(advert.instance) {
+            continue;
+        }
+        // Leaving doesn't need to be filtered, and the peer may have been seen before switching.
+        if !ping.leaving && !shares_room(&profile.borrow().rooms, &ping.rooms) {
+            continue;
         }
 
-        let peer_addr = SocketAddr::from((ip, port));
-        if let Err(_) = to_p2p.send((peer_addr, name.to_string())).await {
+        // Copied from the source rather than built from the IP so that the scope id of an IPv6
+        // link-local address survives.
+        let mut peer_addr = source;
+        peer_addr.set_port(ping.port);
+        if to_p2p.send((peer_addr, ping)).await.is_err() {
             return PingDone::Exiting;
         }
     }
 }
 
+/// Counts pings per source over each `PING_INTERVAL`.
+struct RateLimit {
+    started: Instant,
+    counts: HashMap<IpAddr, u32>,
+}
+
+impl RateLimit {
+    fn allows(&mut self, source: IpAddr) -> bool {
+        if self.started.elapsed() >= PING_INTERVAL {
+            self.started = Instant::now();
+            self.counts.clear();
+        }
+        if self.counts.len() >= MAX_SOURCES && !self.counts.contains_key(&source) {
+            return false;
+        }
+        let count = self.counts.entry(source).or_insert(0);
+        *count += 1;
+        *count <= MAX_PINGS_PER_SOURCE
+    }
+}
+
 // /// Returns None if the other end of the watch channel is dropped.
 // async fn extract_some<T>(watching: WReceiver<Option<T>>) -> Option<T> {
 //     loop {
 //         if let Some(val) = watching.borrow() {
 //             return Some(val);
 //         }
-//         if let Err(_) = watching.changed().await {
+//         if watching.changed().await.is_err() {
 //             return None;
 //         }
 //     }
 // }
 
-async fn task_ping_out(socket: Arc<UdpSocket>, mut to_app: Sender<FromNet>,
-        mut wport: WReceiver<Option<u16>>) -> PingDone {
+#[allow(clippy::too_many_arguments)]
+async fn task_ping_out(family: Family, interfaces: &[String], advert: Advert,
+        mut profile: WReceiver<Profile>, socket: Arc<UdpSocket>,
+        mut to_app: Sender<FromNet>, mut wport: WReceiver<Option<Ports>>,
+        send_nets: WSender<Vec<LocalNet>>) -> PingDone {
     loop {
-        let name = gethostname().into_string().unwrap_or("???".into());
+        // Enumerated every time because interfaces come and go, e.g. when a VPN connects.
+        let nets = local_nets(family, interfaces);
+        if *send_nets.borrow() != nets && !show_local_nets(&mut to_app, family, &nets) {
+            return PingDone::Exiting;
+        }
+        let dests = if nets.is_empty() && interfaces.is_empty() {
+            // Enumeration isn't supported everywhere so fall back on the default interface.
+            vec![fallback_dest(family)]
+        } else {
+            nets.iter().map(|a| a.dest).collect()
+        };
+        if family == Family::V6 {
+            for net in &nets {
+                // Fails harmlessly when the group was already joined on that interface.
+                let _ = socket.join_multicast_v6(&MULTICAST_V6, net.index.unwrap_or(0));
+            }
+        }
+        if send_nets.send(nets).is_err() {
+            return PingDone::Exiting;
+        }
 
         let port = loop {
             let val = *wport.borrow();
-            match val {
+            let port = match (family, val) {
+                (Family::V4, Some(ports)) => Some(ports.v4),
+                (Family::V6, Some(ports)) => ports.v6,
+                (_, None) => None,
+            };
+            match port {
                 Some(a) => break a,
                 None => {
-                    if let Err(_) = wport.changed().await {
+                    if wport.changed().await.is_err() {
                         return PingDone::Exiting;
                     }
                 }
             }
         };
 
-        if let Err(error) = send_ping(&socket, &name, port).await {
+        let current = profile.borrow().clone();
+        let (name, hostname) = ping_names(&current);
+        let ping = Ping {
+            name,
+            hostname,
+            port,
+            instance: Some(advert.instance),
+            fingerprint: Some(advert.fingerprint),
+            rooms: current.rooms,
+            presence: Some(current.presence),
+            note: current.note,
+            ..Default::default()
+        };
+        if let Err(error) = send_ping(&socket, &dests, &ping).await {
             if !show_error(&mut to_app, format!("ping error: {:?}", error)) {
                 return PingDone::Exiting;
             }
             return PingDone::IO(error);
         }
 
-        sleep(PING_INTERVAL).await;
+        select! {
+            _ = sleep(PING_INTERVAL) => {}
+            // so that the others find out about changes right away
+            Ok(()) = profile.changed() => {}
+            changed = wport.changed() => {
+                if changed.is_err() {
+                    // task_p2p is gone so sclan is closing.
+                    let ping = Ping {leaving: true, ..ping};
+                    let _ = send_ping(&socket, &dests, &ping).await;
+                    return PingDone::Exiting;
+                }
+            }
+        }
     }
 }
 
-async fn make_socket() -> IOResult<UdpSocket> {
-    let socket = UdpSocket::bind(("0.0.0.0", PORT)).await?;
-    socket.set_broadcast(true)?;
-    Ok(socket)
+async fn make_socket(family: Family) -> IOResult<UdpSocket> {
+    match family {
+        Family::V4 => {
+            let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+            share_port(&socket)?;
+            socket.set_nonblocking(true)?;
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, PORT)).into())?;
+            socket.set_broadcast(true)?;
+            UdpSocket::from_std(socket.into())
+        }
+        Family::V6 => {
+            // Binding [::] is dual-stack on some platforms, which would collide with the IPv4
+            // socket on the same port, so this one is restricted to IPv6 explicitly.
+            let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+            socket.set_only_v6(true)?;
+            share_port(&socket)?;
+            socket.set_nonblocking(true)?;
+            socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, PORT)).into())?;
+            // Joining on the default interface here ensures at least one membership even when
+            // the interfaces can't be enumerated. The rest are joined when pinging.
+            socket.join_multicast_v6(&MULTICAST_V6, 0)?;
+            // Other copies of sclan on this machine need to hear it. This copy's own pings are
+            // recognized by the instance id.
+            socket.set_multicast_loop_v6(true)?;
+            UdpSocket::from_std(socket.into())
+        }
+    }
 }
 
-async fn send_ping(socket: &Arc<UdpSocket>, local_name: &str, port: u16) -> IOResult<()> {
-    let len = min(local_name.len(), u8::max_value() as usize);
-    let mut message = vec![len as u8];
-    message.extend_from_slice(&local_name.as_bytes()[0..len]);
+/// Succeeds if the ping made it out on at least one network.
+async fn send_ping(socket: &Arc<UdpSocket>, dests: &[SocketAddr], ping: &Ping) -> IOResult<()> {
+    let message = encode_ping(ping);
 
-    message.push(0);
-
-    message.extend_from_slice(&port.to_be_bytes());
+    let mut result = Ok(());
+    let mut sent = false;
+    for dest in dests {
+        // An interface that is down or unplugged shouldn't stop the others from working.
+        match socket.send_to(&message, dest).await {
+            Ok(_) => sent = true,
+            Err(error) => result = Err(error),
+        }
+    }
+    if sent {
+        return Ok(());
+    }
+    result
+}
 
-    socket.send_to(&message, ("255.255.255.255", PORT)).await?;
+/// Lets several copies of sclan on one machine bind the discovery port. Broadcast and multicast
+/// packets get delivered to all of them.
+fn share_port(socket: &Socket) -> IOResult<()> {
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
     Ok(())
 }
 
-fn parse_ping(message: &[u8]) -> Option<(&str, u16)> {
-    let len = *message.get(0)?;
-    let name_bytes = message.get(1..1 + len as usize)?;
-    let name = from_utf8(name_bytes).ok()?;
-
-    if message.get(1 + len as usize) != Some(&0) {
-        // use zero termination just to make it easier to catch malformed packets
-        return None;
+fn fallback_dest(family: Family) -> SocketAddr {
+    match family {
+        Family::V4 => SocketAddr::from(([255, 255, 255, 255], PORT)),
+        Family::V6 => SocketAddr::from((MULTICAST_V6, PORT)),
     }
+}
 
-    let port_index = 2 + len as usize;
-    let port_bytes = message.get(port_index..port_index + 2)?;
+fn local_nets(family: Family, interfaces: &[String]) -> Vec<LocalNet> {
+    let found = match get_if_addrs() {
+        Ok(a) => a,
+        Err(_) => return vec![],
+    };
 
-    let port = u16::from_be_bytes(port_bytes.try_into().unwrap());
-    if port == 0 {
-        return None;
-    }
+    let mut nets = vec![];
+    for interface in found {
+        if interface.is_loopback() {
+            continue;
+        }
+        if !interfaces.is_empty() && !interfaces.iter()
+                .any(|a| *a == interface.name || *a == interface.ip().to_string()) {
+            continue;
+        }
 
-    Some((name, port))
+        match (family, &interface.addr) {
+            (Family::V4, IfAddr::V4(addr)) => {
+                let dest = match addr.broadcast {
+                    Some(a) => a,
+                    // Point-to-point links (/31 and /32) have no broadcast address.
+                    None if addr.prefixlen < 31 => {
+                        Ipv4Addr::from(u32::from(addr.ip) | !u32::from(addr.netmask))
+                    }
+                    None => continue,
+                };
+                nets.push(LocalNet {
+                    ip: addr.ip.into(),
+                    netmask: addr.netmask.into(),
+                    dest: SocketAddr::from((dest, PORT)),
+                    index: interface.index,
+                });
+            }
+            (Family::V6, IfAddr::V6(addr)) => {
+                // Every IPv6 interface has a link-local address so one per interface is enough.
+                let index = match interface.index {
+                    Some(a) if interface.is_link_local() => a,
+                    _ => continue,
+                };
+                if nets.iter().any(|a: &LocalNet| a.index == Some(index)) {
+                    continue;
+                }
+                nets.push(LocalNet {
+                    ip: addr.ip.into(),
+                    netmask: addr.netmask.into(),
+                    dest: SocketAddr::V6(SocketAddrV6::new(MULTICAST_V6, PORT, 0, index)),
+                    index: Some(index),
+                });
+            }
+            _ => {}
+        }
+    }
+    nets
 }
 
-fn local_ip() -> Option<IpAddr> {
-    let socket = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
-    socket.connect("8.8.8.8:80").ok()?;
-    Some(socket.local_addr().ok()?.ip())
+fn from_local_net(source: &SocketAddr, nets: &[LocalNet]) -> bool {
+    nets.iter().any(|net| match (source, net.ip, net.netmask) {
+        (SocketAddr::V4(source), IpAddr::V4(ip), IpAddr::V4(mask)) => {
+            let mask = u32::from(mask);
+            u32::from(*source.ip()) & mask == u32::from(ip) & mask
+        }
+        (SocketAddr::V6(source), IpAddr::V6(_), _) => Some(source.scope_id()) == net.index,
+        _ => false,
+    })
 }
 
-fn show_local_ip(to_app: &mut Sender<FromNet>) {
-    let addr = local_ip()
-        .map(|a| a.to_string())
-        .unwrap_or("".to_string());
-    let _ignore = to_app.send(FromNet::ShowLocalAddress(addr));
+/// false = disconnected
+#[must_use]
+fn show_local_nets(to_app: &mut Sender<FromNet>, family: Family, nets: &[LocalNet]) -> bool {
+    let addresses = nets.iter().map(|a| a.ip).collect();
+    if to_app.send(FromNet::ShowLocalAddresses {ipv6: family == Family::V6, addresses}).is_err() {
+        return false;
+    }
+    true
 }
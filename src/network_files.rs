@@ -0,0 +1,890 @@
+//! Sending and receiving files and folders over the peer connections.
+//!
+//! The sender offers a file and waits for the user on the other side to accept it. The file is
+//! then read and sent in chunks, each one its own message so that typed messages still get
+//! through in between, and the receiver writes them to disk as they arrive. A SHA-256 of the whole
+//! file follows the last chunk for the receiver to check.
+//!
+//! A folder is offered along with a list of everything in it, and then its files are sent one
+//! after the other as though they were one big file.
+//!
+//! When the connection breaks, the sender connects again and repeats the offer, and the receiver
+//! answers with how much it already has so that the transfer picks up from there.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::cmp::min;
+use std::io::{ErrorKind, Result as IOResult};
+use std::fs::Metadata;
+use std::time::Duration;
+use std::sync::mpsc::Sender;
+use tokio::spawn;
+use tokio::fs::{File, OpenOptions, metadata, remove_file, remove_dir_all, rename, create_dir,
+                create_dir_all, read_dir, canonicalize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc::Sender as TSender;
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, timeout};
+use rand::random;
+use ring::digest::{Context, SHA256};
+use qp2p::Connection;
+use crate::network::{FromNet, show_error};
+use crate::network_p2p::send_control;
+use crate::control::{Control, FileControl, Entry, MAX_FILE_NAME_LEN, MAX_ENTRIES, HASH_LEN};
+use crate::envelope::{Kind, seal};
+use crate::identity::Fingerprint;
+
+const CHUNK_LEN: usize = 64 * 1024;
+/// Least time between progress updates for one transfer.
+const PROGRESS_TIME: Duration = Duration::from_millis(250);
+/// A transfer that goes this long without a chunk, or without being able to reconnect, is given
+/// up on.
+const STALL_TIME: Duration = Duration::from_secs(120);
+/// A chunk that takes longer than this to be sent means the connection is gone, even if it hasn't
+/// timed out yet.
+const CHUNK_TIME: Duration = Duration::from_secs(15);
+/// Offers from one peer beyond this many are declined without asking.
+const MAX_OFFERS: usize = 10;
+
+/// What the tasks sending files report when they stop.
+pub type Sent = (u64, Stopped);
+
+pub enum Stopped {
+    Done,
+    /// The connection broke. The transfer can be resumed over a new one.
+    Interrupted,
+    Failed(String),
+}
+
+struct Outgoing {
+    id: u64,
+    connection: Connection,
+    /// The files to send one after the other and their sizes. Just the one unless it's a folder.
+    sources: Vec<(PathBuf, u64)>,
+    /// As it was offered.
+    name: String,
+    size: u64,
+    folder: Option<Vec<Entry>>,
+    /// None until the other side accepts, and again while it's being resumed.
+    task: Option<JoinHandle<()>>,
+    /// When the connection broke, until the other side accepts again.
+    interrupted: Option<Instant>,
+    /// Whether a new connection to the other side is being opened to resume it.
+    reconnecting: bool,
+}
+
+struct Incoming {
+    /// This side's, for the UI.
+    id: u64,
+    /// The sender's.
+    remote_id: u64,
+    address: SocketAddr,
+    connection: Connection,
+    name: String,
+    size: u64,
+    /// Checked to stay inside of the folder.
+    folder: Option<Vec<Entry>>,
+    /// None until the user accepts.
+    receiving: Option<Receiving>,
+}
+
+struct Target {
+    path: PathBuf,
+    size: u64,
+    mode: Option<u32>,
+}
+
+struct Receiving {
+    /// Where the file or folder is written until it's complete.
+    part: PathBuf,
+    folder: bool,
+    /// The files that have something in them, in the order they're sent.
+    targets: Vec<Target>,
+    /// Index of the target being written.
+    current: usize,
+    /// The target being written. Opened when its first bytes arrive.
+    file: Option<File>,
+    /// To the current target so far.
+    written: u64,
+    /// Folders whose permissions are set at the end so that they can be written to until then.
+    dirs: Vec<(PathBuf, Option<u32>)>,
+    /// In total so far. A resumed transfer picks up from here.
+    received: u64,
+    /// Of what's been received so far.
+    hash: Context,
+    last_chunk: Instant,
+    reported: Instant,
+}
+
+pub struct Transfers {
+    outgoing: Vec<Outgoing>,
+    incoming: Vec<Incoming>,
+    /// For the tasks that send files to report back.
+    to_p2p: TSender<Sent>,
+    /// Of this side's key, for the envelopes.
+    sender: Fingerprint,
+}
+
+impl Transfers {
+    pub fn new(to_p2p: TSender<Sent>, sender: Fingerprint) -> Transfers {
+        Transfers {outgoing: vec![], incoming: vec![], to_p2p, sender}
+    }
+
+    /// false = disconnected
+    #[must_use]
+    pub async fn offer(&mut self, to_app: &mut Sender<FromNet>, connection: Connection, id: u64,
+            path: PathBuf) -> bool {
+        let (folder, sources) = match metadata(&path).await {
+            Ok(a) if a.is_file() => (None, vec![(path.clone(), a.len())]),
+            Ok(a) if a.is_dir() => match list_folder(&path).await {
+                Ok((entries, sources)) => (Some(entries), sources),
+                Err(error) => return fail(to_app, id, error),
+            },
+            Ok(_) => return fail(to_app, id, "not a file or folder"),
+            Err(error) => return fail(to_app, id, error.to_string()),
+        };
+        let size = sources.iter().map(|a| a.1).sum();
+        // Canonical so that `.` and `..` get the folder's actual name.
+        let name = canonicalize(&path).await.ok()
+            .and_then(|a| a.file_name().map(|a| a.to_string_lossy().into_owned()))
+            .unwrap_or_default();
+        let name = cut_name(&name).to_string();
+
+        let offer = Control::File(FileControl::Offer {
+            id,
+            size,
+            name: name.clone(),
+            folder: folder.clone(),
+        });
+        if let Err(error) = connection.send(seal(self.sender, Kind::Control(offer)).into()).await {
+            return fail(to_app, id, error.to_string());
+        }
+        self.outgoing.push(Outgoing {
+            id,
+            connection,
+            sources,
+            name,
+            size,
+            folder,
+            task: None,
+            interrupted: None,
+            reconnecting: false,
+        });
+        if to_app.send(FromNet::FileOffered {id, size}).is_err() {
+            return false;
+        }
+        true
+    }
+
+    /// The peers that transfers are waiting to be resumed with, once each. They count as being
+    /// reconnected to until `resume` is called for them.
+    pub fn interrupted(&mut self) -> Vec<SocketAddr> {
+        let mut addresses = vec![];
+        for transfer in &mut self.outgoing {
+            if transfer.interrupted.is_none() || transfer.reconnecting {
+                continue;
+            }
+            transfer.reconnecting = true;
+            let address = transfer.connection.remote_address();
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
+        }
+        addresses
+    }
+
+    /// Repeats the offers to the peer over the new connection so that it says how much it has.
+    /// Tried again on a later tick if there's no connection or they can't be sent.
+    pub async fn resume(&mut self, address: SocketAddr, connection: Option<Connection>) {
+        for transfer in &mut self.outgoing {
+            if !transfer.reconnecting || transfer.connection.remote_address() != address {
+                continue;
+            }
+            transfer.reconnecting = false;
+            if let Some(connection) = &connection {
+                transfer.connection = connection.clone();
+                let offer = Control::File(FileControl::Offer {
+                    id: transfer.id,
+                    size: transfer.size,
+                    name: transfer.name.clone(),
+                    folder: transfer.folder.clone(),
+                });
+                let bytes = seal(self.sender, Kind::Control(offer));
+                let _ = transfer.connection.send(bytes.into()).await;
+            }
+        }
+    }
+
+    /// Starts writing an offered file to disk.
+    /// false = disconnected
+    #[must_use]
+    pub async fn accept(&mut self, to_app: &mut Sender<FromNet>, id: u64) -> bool {
+        let found = self.incoming.iter().position(|r| r.id == id && r.receiving.is_none());
+        let index = if let Some(a) = found {
+            a
+        } else {
+            return true;
+        };
+
+        let transfer = &mut self.incoming[index];
+        let folder = transfer.folder.is_some();
+        let part = unused_path(&format!("{}.part", transfer.name), folder);
+        let mut receiving = Receiving {
+            part,
+            folder,
+            targets: vec![],
+            current: 0,
+            file: None,
+            written: 0,
+            dirs: vec![],
+            received: 0,
+            hash: Context::new(&SHA256),
+            last_chunk: Instant::now(),
+            reported: Instant::now(),
+        };
+        if let Err(error) = prepare(&mut receiving, transfer.size, &transfer.folder).await {
+            let transfer = self.incoming.remove(index);
+            send_control(transfer.connection, self.sender, cancel(transfer.remote_id));
+            discard(Some(receiving)).await;
+            return fail(to_app, id, error);
+        }
+        transfer.receiving = Some(receiving);
+        let accept = Control::File(FileControl::Accept {id: transfer.remote_id, offset: 0});
+        send_control(transfer.connection.clone(), self.sender, accept);
+        true
+    }
+
+    /// Declines or stops the transfer with this id from the UI.
+    pub async fn cancel(&mut self, id: u64) {
+        if let Some(index) = self.outgoing.iter().position(|r| r.id == id) {
+            let transfer = self.outgoing.remove(index);
+            if let Some(task) = transfer.task {
+                task.abort();
+            }
+            send_control(transfer.connection, self.sender, cancel(id));
+        }
+        if let Some(index) = self.incoming.iter().position(|r| r.id == id) {
+            let transfer = self.incoming.remove(index);
+            send_control(transfer.connection, self.sender, cancel(transfer.remote_id));
+            discard(transfer.receiving).await;
+        }
+    }
+
+    /// For when the peer sent a file control. It's been checked that the peer is allowed to.
+    /// false = disconnected
+    #[must_use]
+    pub async fn receive(&mut self, to_app: &mut Sender<FromNet>, connection: Connection,
+            control: FileControl) -> bool {
+        let address = connection.remote_address();
+        match control {
+            FileControl::Offer {id: remote_id, size, name, folder} => {
+                let found = self.incoming.iter_mut()
+                    .find(|r| r.address == address && r.remote_id == remote_id);
+                if let Some(transfer) = found {
+                    // The sender reconnected.
+                    transfer.connection = connection.clone();
+                    if let Some(receiving) = &transfer.receiving {
+                        let offset = receiving.received;
+                        let accept = Control::File(FileControl::Accept {id: remote_id, offset});
+                        send_control(connection, self.sender, accept);
+                    }
+                    return true;
+                }
+
+                let offers = self.incoming.iter().filter(|r| r.address == address).count();
+                if offers >= MAX_OFFERS {
+                    send_control(connection, self.sender, cancel(remote_id));
+                    return true;
+                }
+
+                if let Some(entries) = &folder {
+                    if let Err(error) = check_folder(entries, size) {
+                        send_control(connection, self.sender, cancel(remote_id));
+                        return show_error(to_app, format!("declined a folder from {}: {}",
+                            address, error));
+                    }
+                }
+
+                let id = random();
+                let name = file_name(&name);
+                let is_folder = folder.is_some();
+                self.incoming.push(Incoming {
+                    id,
+                    remote_id,
+                    address,
+                    connection,
+                    name: name.clone(),
+                    size,
+                    folder,
+                    receiving: None,
+                });
+                let offer = FromNet::FileOffer {id, source: address, name, size, folder: is_folder};
+                if to_app.send(offer).is_err() {
+                    return false;
+                }
+            }
+            FileControl::Accept {id, offset} => {
+                let found = self.outgoing.iter().position(|r| r.id == id
+                    && r.connection.remote_address() == address);
+                let index = match found {
+                    // Only started once even if the peer accepts again.
+                    Some(a) if self.outgoing[a].task.is_none() => a,
+                    _ => return true,
+                };
+                if offset > self.outgoing[index].size {
+                    let transfer = self.outgoing.remove(index);
+                    send_control(transfer.connection, self.sender, cancel(id));
+                    return fail(to_app, id, "the other computer asked for more than the file");
+                }
+
+                let transfer = &mut self.outgoing[index];
+                transfer.interrupted = None;
+                transfer.task = Some(spawn(task_send_file(transfer.connection.clone(), self.sender,
+                    id, transfer.sources.clone(), offset, to_app.clone(), self.to_p2p.clone())));
+                if to_app.send(FromNet::FileProgress {id, done: offset}).is_err() {
+                    return false;
+                }
+            }
+            FileControl::Cancel {id} => {
+                let found = self.outgoing.iter().position(|r| r.id == id
+                    && r.connection.remote_address() == address);
+                if let Some(index) = found {
+                    let transfer = self.outgoing.remove(index);
+                    let error = match transfer.task {
+                        Some(task) => {
+                            task.abort();
+                            "cancelled by the other computer"
+                        }
+                        None if transfer.interrupted.is_some() => {
+                            "cancelled by the other computer"
+                        }
+                        None => "declined",
+                    };
+                    return fail(to_app, id, error);
+                }
+
+                let found = self.incoming.iter()
+                    .position(|r| r.remote_id == id && r.address == address);
+                if let Some(index) = found {
+                    let transfer = self.incoming.remove(index);
+                    discard(transfer.receiving).await;
+                    return fail(to_app, transfer.id, "cancelled by the other computer");
+                }
+            }
+            FileControl::Chunk {id, offset, data} => {
+                let found = self.incoming.iter().position(|r| r.remote_id == id
+                    && r.address == address && r.receiving.is_some());
+                if let Some(index) = found {
+                    return self.write_chunk(to_app, index, offset, &data).await;
+                }
+            }
+            FileControl::Finish {id, hash} => {
+                let found = self.incoming.iter().position(|r| r.remote_id == id
+                    && r.address == address && r.receiving.is_some());
+                if let Some(index) = found {
+                    return self.finish(to_app, index, hash).await;
+                }
+            }
+        }
+        true
+    }
+
+    /// false = disconnected
+    #[must_use]
+    async fn write_chunk(&mut self, to_app: &mut Sender<FromNet>, index: usize, offset: u64,
+            data: &[u8]) -> bool {
+        let transfer = &mut self.incoming[index];
+        let receiving = transfer.receiving.as_mut().unwrap();
+        // Left over from before the transfer was resumed.
+        if offset != receiving.received {
+            return true;
+        }
+
+        let result = if receiving.received + data.len() as u64 > transfer.size {
+            Err("the file is bigger than it was said to be".to_string())
+        } else {
+            write_data(receiving, data).await
+        };
+        if let Err(error) = result {
+            return self.give_up(to_app, index, error).await;
+        }
+
+        receiving.hash.update(data);
+        receiving.received += data.len() as u64;
+        receiving.last_chunk = Instant::now();
+
+        if receiving.reported.elapsed() >= PROGRESS_TIME {
+            receiving.reported = Instant::now();
+            let progress = FromNet::FileProgress {id: transfer.id, done: receiving.received};
+            if to_app.send(progress).is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Checks a file that's been received in full against the sender's hash and moves it to
+    /// where it's kept.
+    /// false = disconnected
+    #[must_use]
+    async fn finish(&mut self, to_app: &mut Sender<FromNet>, index: usize,
+            hash: [u8; HASH_LEN]) -> bool {
+        let transfer = &self.incoming[index];
+        if transfer.receiving.as_ref().is_some_and(|a| a.received != transfer.size) {
+            return self.give_up(to_app, index, "part of the file went missing".into()).await;
+        }
+        let transfer = self.incoming.remove(index);
+        let receiving = transfer.receiving.unwrap();
+
+        if receiving.hash.clone().finish().as_ref() != hash {
+            discard(Some(receiving)).await;
+            return fail(to_app, transfer.id, "the checksum didn't match, so the file was damaged \
+                on the way and has been thrown away");
+        }
+
+        // Every target was closed as soon as it was complete. Deeper folders come later in the
+        // list, so going backwards sets their permissions before their parents'.
+        for (path, mode) in receiving.dirs.iter().rev() {
+            if let Err(error) = set_mode(path, *mode).await {
+                return fail(to_app, transfer.id, format!("{}: {}", path.display(), error));
+            }
+        }
+
+        // Picked only now so that a file that showed up in the meantime isn't written over.
+        let path = unused_path(&transfer.name, receiving.folder);
+        if let Err(error) = rename(&receiving.part, &path).await {
+            return fail(to_app, transfer.id, format!("{}: {}", path.display(), error));
+        }
+
+        let path = Some(path.display().to_string());
+        if to_app.send(FromNet::FileDone {id: transfer.id, path}).is_err() {
+            return false;
+        }
+        true
+    }
+
+    /// Stops receiving a file and tells the sender.
+    /// false = disconnected
+    #[must_use]
+    async fn give_up(&mut self, to_app: &mut Sender<FromNet>, index: usize, error: String)
+            -> bool {
+        let transfer = self.incoming.remove(index);
+        send_control(transfer.connection, self.sender, cancel(transfer.remote_id));
+        discard(transfer.receiving).await;
+        fail(to_app, transfer.id, error)
+    }
+
+    /// For when a task sending a file stops.
+    /// false = disconnected
+    #[must_use]
+    pub fn sent(&mut self, to_app: &mut Sender<FromNet>, (id, stopped): Sent) -> bool {
+        let found = self.outgoing.iter().position(|r| r.id == id);
+        let index = if let Some(a) = found {
+            a
+        } else {
+            // Cancelled in the meantime.
+            return true;
+        };
+
+        match stopped {
+            Stopped::Done => {
+                self.outgoing.remove(index);
+                if to_app.send(FromNet::FileDone {id, path: None}).is_err() {
+                    return false;
+                }
+                true
+            }
+            Stopped::Interrupted => {
+                let transfer = &mut self.outgoing[index];
+                transfer.task = None;
+                transfer.interrupted = Some(Instant::now());
+                true
+            }
+            Stopped::Failed(error) => {
+                let transfer = self.outgoing.remove(index);
+                send_control(transfer.connection, self.sender, cancel(id));
+                fail(to_app, id, error)
+            }
+        }
+    }
+
+    /// Gives up on the files that stopped arriving and the ones that couldn't be resumed.
+    /// false = disconnected
+    #[must_use]
+    pub async fn cull(&mut self, to_app: &mut Sender<FromNet>, now: Instant) -> bool {
+        while let Some(index) = self.incoming.iter().position(|r| {
+            r.receiving.as_ref().is_some_and(|a| now.duration_since(a.last_chunk) >= STALL_TIME)
+        }) {
+            if !self.give_up(to_app, index, "the other computer stopped sending".into()).await {
+                return false;
+            }
+        }
+
+        while let Some(index) = self.outgoing.iter().position(|r| {
+            r.interrupted.is_some_and(|a| now.duration_since(a) >= STALL_TIME)
+        }) {
+            let transfer = self.outgoing.remove(index);
+            if !fail(to_app, transfer.id, "lost the connection") {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Deletes the partly received files, for when sclan is closing.
+    pub async fn discard_all(&mut self) {
+        for transfer in self.incoming.drain(..) {
+            discard(transfer.receiving).await;
+        }
+    }
+}
+
+fn cancel(id: u64) -> Control {
+    Control::File(FileControl::Cancel {id})
+}
+
+/// false = disconnected
+#[must_use]
+fn fail(to_app: &mut Sender<FromNet>, id: u64, error: impl Into<String>) -> bool {
+    if to_app.send(FromNet::FileFailed {id, error: error.into()}).is_err() {
+        return false;
+    }
+    true
+}
+
+async fn discard(receiving: Option<Receiving>) {
+    if let Some(receiving) = receiving {
+        drop(receiving.file);
+        if receiving.folder {
+            let _ = remove_dir_all(&receiving.part).await;
+        } else {
+            let _ = remove_file(&receiving.part).await;
+        }
+    }
+}
+
+/// Creates the part file, or the part folder and everything in it that won't get any chunks.
+async fn prepare(receiving: &mut Receiving, size: u64, folder: &Option<Vec<Entry>>)
+        -> Result<(), String> {
+    let part = receiving.part.clone();
+    let entries = match folder {
+        Some(a) => a,
+        None => {
+            // Reserves the name.
+            OpenOptions::new().write(true).create_new(true).open(&part).await
+                .map_err(|a| format!("{}: {}", part.display(), a))?;
+            if size > 0 {
+                receiving.targets.push(Target {path: part, size, mode: None});
+            }
+            return Ok(());
+        }
+    };
+
+    create_dir(&part).await.map_err(|a| format!("{}: {}", part.display(), a))?;
+    for entry in entries {
+        // Checked when the offer arrived.
+        let path = part.join(entry_path(&entry.path).unwrap());
+        let result = async {
+            if entry.dir {
+                create_dir_all(&path).await?;
+                receiving.dirs.push((path.clone(), entry.mode));
+                return Ok(());
+            }
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent).await?;
+            }
+            if entry.size > 0 {
+                receiving.targets.push(Target {path: path.clone(), size: entry.size,
+                    mode: entry.mode});
+                return Ok(());
+            }
+            File::create(&path).await?;
+            set_mode(&path, entry.mode).await
+        }.await;
+        result.map_err(|a| format!("{}: {}", path.display(), a))?;
+    }
+    Ok(())
+}
+
+/// Writes the data to the targets that it belongs to, moving on to the next one each time one is
+/// complete.
+async fn write_data(receiving: &mut Receiving, mut data: &[u8]) -> Result<(), String> {
+    while !data.is_empty() {
+        let target = &receiving.targets[receiving.current];
+        let result = async {
+            let file = match &mut receiving.file {
+                Some(a) => a,
+                None => {
+                    let file = OpenOptions::new().write(true).create(true).truncate(true)
+                        .open(&target.path).await?;
+                    receiving.file.insert(file)
+                }
+            };
+            let len = min(target.size - receiving.written, data.len() as u64) as usize;
+            file.write_all(&data[..len]).await?;
+            receiving.written += len as u64;
+            data = &data[len..];
+
+            if receiving.written == target.size {
+                file.sync_all().await?;
+                receiving.file = None;
+                set_mode(&target.path, target.mode).await?;
+                receiving.current += 1;
+                receiving.written = 0;
+            }
+            IOResult::Ok(())
+        }.await;
+        result.map_err(|a| format!("{}: {}", target.path.display(), a))?;
+    }
+    Ok(())
+}
+
+async fn task_send_file(connection: Connection, sender: Fingerprint, id: u64,
+        sources: Vec<(PathBuf, u64)>, offset: u64, to_app: Sender<FromNet>, to_p2p: TSender<Sent>) {
+    let stopped = send_file(&connection, sender, id, &sources, offset, &to_app).await;
+    let _ = to_p2p.send((id, stopped)).await;
+}
+
+/// Sends the files from the offset on as though they were one, and then the hash of all of them.
+/// Waits for each chunk to be sent before reading the next one so that they're never held in
+/// memory.
+async fn send_file(connection: &Connection, sender: Fingerprint, id: u64,
+        sources: &[(PathBuf, u64)], offset: u64, to_app: &Sender<FromNet>) -> Stopped {
+    let mut hash = Context::new(&SHA256);
+    let mut buffer = vec![0; CHUNK_LEN];
+    let mut done = 0;
+    let mut reported = Instant::now();
+    for (path, size) in sources {
+        let mut file = match File::open(path).await {
+            Ok(a) => a,
+            Err(error) => return Stopped::Failed(format!("{}: {}", path.display(), error)),
+        };
+        let mut left = *size;
+        while left > 0 {
+            // What the other side already has is only read for the hash.
+            let end = if done < offset { offset - done } else { u64::MAX };
+            let len = min(min(CHUNK_LEN as u64, left), end) as usize;
+            match file.read_exact(&mut buffer[..len]).await {
+                Ok(_) => {}
+                Err(error) if error.kind() == ErrorKind::UnexpectedEof => {
+                    return Stopped::Failed(format!("{} got shorter while it was being sent",
+                        path.display()));
+                }
+                Err(error) => return Stopped::Failed(format!("{}: {}", path.display(), error)),
+            }
+            hash.update(&buffer[..len]);
+
+            if done >= offset {
+                let data = buffer[..len].to_vec();
+                let chunk = Control::File(FileControl::Chunk {id, offset: done, data});
+                if !send_within(connection, sender, chunk).await {
+                    return Stopped::Interrupted;
+                }
+            }
+            done += len as u64;
+            left -= len as u64;
+
+            if done > offset && reported.elapsed() >= PROGRESS_TIME {
+                reported = Instant::now();
+                let _ = to_app.send(FromNet::FileProgress {id, done});
+            }
+        }
+    }
+
+    let hash = hash.finish().as_ref().try_into().unwrap();
+    let finish = Control::File(FileControl::Finish {id, hash});
+    if !send_within(connection, sender, finish).await {
+        return Stopped::Interrupted;
+    }
+    Stopped::Done
+}
+
+/// false = the connection broke or stopped responding
+async fn send_within(connection: &Connection, sender: Fingerprint, control: Control) -> bool {
+    let bytes = seal(sender, Kind::Control(control));
+    let sent = timeout(CHUNK_TIME, connection.send(bytes.into())).await;
+    matches!(sent, Ok(Ok(_)))
+}
+
+/// Keeps only the last part of the name so that a file can't be saved outside of the current
+/// directory, whatever the sender's system uses to separate folders.
+fn file_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or("");
+    let name = name.chars()
+        .filter(|a| !a.is_control() && !":*?\"<>|".contains(*a))
+        .collect::<String>();
+    let name = cut_name(name.trim());
+    if name.is_empty() || name == "." || name == ".." {
+        return "file".into();
+    }
+    name.to_string()
+}
+
+/// Cut short on a character boundary.
+fn cut_name(name: &str) -> &str {
+    let mut end = min(name.len(), MAX_FILE_NAME_LEN);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+/// In the current directory, with a number added before the extension if the name is taken.
+/// Folders don't have extensions.
+fn unused_path(name: &str, folder: bool) -> PathBuf {
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() && !folder => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+    let mut path = Path::new(".").join(name);
+    let mut number = 2;
+    while path.exists() {
+        path = Path::new(".").join(format!("{} ({}){}", stem, number, extension));
+        number += 1;
+    }
+    path
+}
+
+/// Lists everything in the folder, with each folder coming before what's in it. Links are left
+/// out so that nothing outside of the folder gets sent.
+async fn list_folder(root: &Path) -> Result<(Vec<Entry>, Vec<(PathBuf, u64)>), String> {
+    let mut entries = vec![];
+    let mut sources = vec![];
+    let mut pending = vec![(root.to_path_buf(), String::new())];
+    while let Some((dir, prefix)) = pending.pop() {
+        let error = |a: std::io::Error| format!("{}: {}", dir.display(), a);
+        let mut children = vec![];
+        let mut list = read_dir(&dir).await.map_err(error)?;
+        while let Some(child) = list.next_entry().await.map_err(error)? {
+            children.push(child);
+        }
+        children.sort_by_key(|a| a.file_name());
+
+        for child in children {
+            // Doesn't follow links.
+            let metadata = child.metadata().await.map_err(error)?;
+            let name = child.file_name().to_string_lossy().into_owned();
+            let path = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+
+            if metadata.is_dir() {
+                entries.push(Entry {path: path.clone(), dir: true, size: 0, mode: mode(&metadata)});
+                pending.push((child.path(), path));
+            } else if metadata.is_file() {
+                entries.push(Entry {path, dir: false, size: metadata.len(), mode: mode(&metadata)});
+                sources.push((child.path(), metadata.len()));
+            }
+        }
+        if entries.len() > MAX_ENTRIES {
+            return Err(format!("folders can't have more than {} things in them", MAX_ENTRIES));
+        }
+    }
+    Ok((entries, sources))
+}
+
+/// Makes sure that a folder being offered can't write anywhere outside of itself and that its
+/// size adds up.
+fn check_folder(entries: &[Entry], size: u64) -> Result<(), String> {
+    if entries.len() > MAX_ENTRIES {
+        return Err(format!("it has more than {} things in it", MAX_ENTRIES));
+    }
+    let mut total = 0u64;
+    for entry in entries {
+        if entry_path(&entry.path).is_none() {
+            return Err(format!("it has an unsafe path: {}", entry.path));
+        }
+        if entry.dir && entry.size > 0 {
+            return Err("its size doesn't add up".into());
+        }
+        total = total.checked_add(entry.size).ok_or("its size is too big")?;
+    }
+    if total != size {
+        return Err("its size doesn't add up".into());
+    }
+    Ok(())
+}
+
+/// Relative to the folder being received. None for paths that are absolute, go up out of it with
+/// `..` or have anything else in them that could end up somewhere else.
+fn entry_path(path: &str) -> Option<PathBuf> {
+    let mut result = PathBuf::new();
+    for part in path.split('/') {
+        let unsafe_part = part.is_empty() || part == "." || part == ".."
+            || part.contains(['\\', ':']) || part.chars().any(char::is_control);
+        if unsafe_part {
+            return None;
+        }
+        result.push(part);
+    }
+    Some(result)
+}
+
+#[cfg(unix)]
+fn mode(metadata: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o777)
+}
+
+#[cfg(not(unix))]
+fn mode(_metadata: &Metadata) -> Option<u32> {
+    None
+}
+
+/// Only the permission bits are kept. Does nothing on systems that don't have them.
+#[cfg(unix)]
+async fn set_mode(path: &Path, mode: Option<u32>) -> IOResult<()> {
+    use std::fs::Permissions;
+    use std::os::unix::fs::PermissionsExt;
+    match mode {
+        Some(mode) => tokio::fs::set_permissions(path, Permissions::from_mode(mode & 0o777)).await,
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn set_mode(_path: &Path, _mode: Option<u32>) -> IOResult<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, size: u64) -> Entry {
+        Entry {path: path.into(), dir: false, size, mode: None}
+    }
+
+    #[test]
+    fn safe_paths() {
+        assert_eq!(entry_path("a"), Some(PathBuf::from("a")));
+        assert_eq!(entry_path("a/b c/d.txt"), Some(["a", "b c", "d.txt"].iter().collect()));
+        assert_eq!(entry_path("..a/b.."), Some(["..a", "b.."].iter().collect()));
+    }
+
+    #[test]
+    fn unsafe_paths() {
+        for path in ["", "..", "a/../b", "a/..", ".", "a/./b", "/etc/passwd", "a//b", "a/",
+                "a\\..\\b", "..\\b", "C:", "C:/Windows", "c:file", "\\\\server\\share",
+                "a/b:stream", "a\nb"] {
+            assert_eq!(entry_path(path), None, "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn folder_with_unsafe_path_is_declined() {
+        let entries = [file("a", 1), file("../b", 2)];
+        assert!(check_folder(&entries, 3).is_err());
+        let entries = [file("a", 1), file("/b", 2)];
+        assert!(check_folder(&entries, 3).is_err());
+        let entries = [file("a", 1), file("D:\\b", 2)];
+        assert!(check_folder(&entries, 3).is_err());
+    }
+
+    #[test]
+    fn folder_size_has_to_add_up() {
+        let dir = Entry {path: "a".into(), dir: true, size: 0, mode: None};
+        let entries = [dir, file("a/b", 1), file("a/c", 2)];
+        assert!(check_folder(&entries, 3).is_ok());
+        assert!(check_folder(&entries, 4).is_err());
+        assert!(check_folder(&[file("a", u64::MAX), file("b", 1)], 0).is_err());
+    }
+}
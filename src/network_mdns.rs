@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::mpsc::Sender;
+use tokio::select;
+use tokio::time::{interval, MissedTickBehavior};
+use tokio::sync::watch::Receiver as WReceiver;
+use tokio::sync::mpsc::Sender as TSender;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo, IfKind};
+use crate::network::{show_error, FromNet};
+use crate::network_broadcast::{Advert, Profile, PING_INTERVAL, ping_names};
+use crate::network_p2p::Ports;
+use crate::ping::{Ping, Presence, PROTOCOL_VERSION, shares_room, strip_controls};
+use crate::identity::{to_hex, from_hex};
+
+const SERVICE_TYPE: &str = "_sclan._udp.local.";
+
+/// Advertises this machine over multicast DNS and reports the other machines that do the same.
+/// Peers found this way go to `task_p2p` just like the ones found by pings.
+pub async fn task_mdns(interfaces: Vec<String>, advert: Advert,
+        mut profile: WReceiver<Profile>, mut to_app: Sender<FromNet>,
+        mut wport: WReceiver<Option<Ports>>, to_p2p: TSender<(SocketAddr, Ping)>) {
+    let daemon = match ServiceDaemon::new() {
+        Ok(a) => a,
+        Err(error) => {
+            let _ignore = show_error(&mut to_app, format!("mDNS error: {}", error));
+            return;
+        }
+    };
+    if !interfaces.is_empty() {
+        let _ = daemon.disable_interface(IfKind::All);
+        for interface in interfaces {
+            let kind = match interface.parse::<IpAddr>() {
+                Ok(ip) => IfKind::Addr(ip),
+                Err(_) => IfKind::Name(interface),
+            };
+            let _ = daemon.enable_interface(kind);
+        }
+    }
+    let browse = match daemon.browse(SERVICE_TYPE) {
+        Ok(a) => a,
+        Err(error) => {
+            let _ignore = show_error(&mut to_app, format!("mDNS error: {}", error));
+            return;
+        }
+    };
+
+    // mDNS only reports a service once when it shows up, but task_p2p forgets peers that it
+    // hasn't heard from in a while, so the ones that are still around get repeated.
+    let mut found = HashMap::<String, Vec<(SocketAddr, Ping)>>::new();
+    let mut repeat = interval(PING_INTERVAL);
+    repeat.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    let mut registered = None;
+    let mut fullname = None;
+    loop {
+        let names = ping_names(&profile.borrow());
+        let ports = *wport.borrow();
+        if let Some(ports) = ports {
+            let registration = (names, ports, profile.borrow().clone());
+            if registered.as_ref() != Some(&registration) {
+                match register(&daemon, advert, &registration.0, registration.1,
+                        &registration.2) {
+                    Ok(a) => fullname = Some(a),
+                    Err(error) => {
+                        if !show_error(&mut to_app, format!("mDNS error: {}", error)) {
+                            return;
+                        }
+                    }
+                }
+                registered = Some(registration);
+            }
+        }
+
+        select! {
+            changed = wport.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+            }
+
+            Ok(()) = profile.changed() => {}
+
+            event = browse.recv_async() => {
+                match event {
+                    Ok(ServiceEvent::ServiceResolved(info)) => {
+                        let pings = to_pings(&info);
+                        if pings.iter().any(|(_, ping)| ping.instance == Some(advert.instance)) {
+                            continue;
+                        }
+                        found.insert(info.get_fullname().to_string(), pings);
+                    }
+                    Ok(ServiceEvent::ServiceRemoved(_, fullname)) => {
+                        for (address, mut ping) in found.remove(&fullname).unwrap_or_default() {
+                            ping.leaving = true;
+                            if to_p2p.send((address, ping)).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(_) => break,
+                }
+            }
+
+            _ = repeat.tick() => {
+                let found = found.values().flatten()
+                    .filter(|(_, ping)| shares_room(&profile.borrow().rooms, &ping.rooms));
+                for (address, ping) in found {
+                    // Gone along with task_p2p. The closed port watch ends the loop.
+                    if to_p2p.send((*address, ping.clone())).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    // task_p2p is gone so sclan is closing. Unregistering tells the others that it's leaving.
+    if let Some(fullname) = fullname {
+        if let Ok(receiver) = daemon.unregister(&fullname) {
+            let _ = receiver.recv_async().await;
+        }
+    }
+    let _ = daemon.shutdown();
+}
+
+/// Returns the full name of the service.
+fn register(daemon: &ServiceDaemon, advert: Advert, names: &(String, Option<String>),
+        ports: Ports, profile: &Profile) -> mdns_sd::Result<String> {
+    let id = format!("{:016x}", advert.instance);
+    let mut properties = HashMap::new();
+    properties.insert("name".to_string(), names.0.clone());
+    if let Some(hostname) = &names.1 {
+        properties.insert("hostname".to_string(), hostname.clone());
+    }
+    properties.insert("instance".to_string(), id.clone());
+    properties.insert("version".to_string(), PROTOCOL_VERSION.to_string());
+    properties.insert("fingerprint".to_string(), to_hex(&advert.fingerprint));
+    if let Some(port) = ports.v6 {
+        properties.insert("port6".to_string(), port.to_string());
+    }
+    if !profile.rooms.is_empty() {
+        properties.insert("rooms".to_string(), profile.rooms.join(","));
+    }
+    properties.insert("presence".to_string(), profile.presence.name().to_string());
+    if !profile.note.is_empty() {
+        properties.insert("note".to_string(), profile.note.clone());
+    }
+
+    // The instance id keeps the names unique when there are several copies on one machine.
+    let info = ServiceInfo::new(SERVICE_TYPE, &id, &format!("{}.local.", id), (), ports.v4,
+        properties)?
+        .enable_addr_auto();
+    let fullname = info.get_fullname().to_string();
+    daemon.register(info)?;
+    Ok(fullname)
+}
+
+/// One for each address the service can be reached at.
+fn to_pings(info: &ServiceInfo) -> Vec<(SocketAddr, Ping)> {
+    let ping = Ping {
+        name: strip_controls(info.get_property_val_str("name").unwrap_or("")),
+        hostname: info.get_property_val_str("hostname").map(strip_controls),
+        port: info.get_port(),
+        version: info.get_property_val_str("version")
+            .and_then(|a| a.parse().ok()).unwrap_or(0),
+        instance: info.get_property_val_str("instance")
+            .and_then(|a| u64::from_str_radix(a, 16).ok()),
+        fingerprint: info.get_property_val_str("fingerprint")
+            .and_then(from_hex).and_then(|a| a.try_into().ok()),
+        leaving: false,
+        rooms: info.get_property_val_str("rooms").unwrap_or("").split(',')
+            .map(strip_controls)
+            .filter(|a| !a.is_empty())
+            .collect(),
+        presence: info.get_property_val_str("presence")
+            .map(|a| Presence::from_name(a).unwrap_or_default()),
+        note: strip_controls(info.get_property_val_str("note").unwrap_or("")),
+    };
+    let port6 = info.get_property_val_str("port6").and_then(|a| a.parse::<u16>().ok());
+
+    let mut pings = vec![];
+    for ip in info.get_addresses() {
+        let port = match ip {
+            IpAddr::V4(_) => ping.port,
+            // Link-local addresses can't be used without knowing the interface they're on.
+            IpAddr::V6(ip) if ip.segments()[0] & 0xffc0 == 0xfe80 => continue,
+            IpAddr::V6(_) => match port6 {
+                Some(a) => a,
+                None => continue,
+            },
+        };
+        let mut ping = ping.clone();
+        ping.port = port;
+        pings.push((SocketAddr::from((*ip, port)), ping));
+    }
+    pings
+}
@@ -1,46 +1,374 @@
-use std::net::{SocketAddr, IpAddr};
+use std::net::{SocketAddr, IpAddr, Ipv6Addr};
+use std::future::pending;
 use std::time::Duration;
+use std::mem::take;
 use std::sync::mpsc::{Sender, Receiver};
+use std::sync::Arc;
 use tokio::{spawn, select};
-use tokio::time::{sleep, interval, MissedTickBehavior, Instant};
+use tokio::net::lookup_host;
+use tokio::time::{sleep, interval, timeout, MissedTickBehavior, Instant};
 use tokio::task::spawn_blocking;
 use tokio::sync::mpsc::{channel, Receiver as TReceiver, Sender as TSender};
 use tokio::runtime::Handle;
 use tokio::sync::watch::Sender as WSender;
-use qp2p::{Config, Endpoint, ConnectionIncoming, Connection};
-use crate::network::{FromNet, ToNet, show_error};
+use rand::random;
+use qp2p::{Config, Endpoint, ConnectionIncoming, Connection, IncomingConnections};
+use if_addrs::get_if_addrs;
+use crate::network::{FromNet, ToNet, Policy, Rejected, show_error, show_status};
 use crate::log::ToLog;
+use crate::config::DEFAULT_PORT;
+use crate::ping::{Ping, Presence, shares_room};
+use crate::network_broadcast::Profile;
+use crate::identity::{Identity, Fingerprint, check_proof, fingerprint, to_hex};
+use crate::known_peers::peer_key;
+use crate::control::{Control, FileControl, CHALLENGE_LEN};
+use crate::envelope::{Envelope, Kind, is_envelope, seal, seal_with_id, open};
+use crate::network_files::{Transfers, Sent};
+use crate::groups::Group;
 
 const PEER_IDLE_TIME: Duration = Duration::from_secs(18);
+/// Peers whose pings claim a key aren't shown until they prove it or this much time passes, so
+/// that the UI doesn't warn about them while the handshake is still going.
+const PROOF_WAIT: Duration = Duration::from_secs(5);
+/// How long to spend telling connected peers that this one is leaving.
+const GOODBYE_TIME: Duration = Duration::from_millis(500);
+/// Peers found by discovery or connecting on their own beyond this many are turned away. Peers
+/// added by hand always get in.
+const MAX_PEERS: usize = 500;
+/// Messages from unapproved peers beyond this many are dropped.
+const MAX_HELD: usize = 100;
+/// Sent messages waiting for receipts beyond this many are forgotten, oldest first.
+const MAX_AWAITING: usize = 1000;
+/// Sent messages beyond this many can't be edited or retracted anymore, oldest first.
+const MAX_EDITABLE: usize = 1000;
+
+/// Ports of the local endpoints, for advertising in pings.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Ports {
+    pub v4: u16,
+    /// None when the machine has no IPv6 support.
+    pub v6: Option<u16>,
+}
+
+#[derive(Clone)]
+struct Nodes {
+    v4: Endpoint,
+    v6: Option<Endpoint>,
+}
+
+impl Nodes {
+    fn for_address(&self, address: &SocketAddr) -> Option<&Endpoint> {
+        match address {
+            SocketAddr::V4(_) => Some(&self.v4),
+            SocketAddr::V6(_) => self.v6.as_ref(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Origin {
+    /// Found by a ping. Forgotten when the pings stop.
+    Discovered,
+    /// Added by the user. Never forgotten.
+    Manual,
+    /// Connected to this machine without having sent a ping, probably because it added this
+    /// machine by hand. Kept until a ping shows up for it or the connection closes.
+    Incoming,
+}
 
 struct PeerKnown {
     name: String,
     address: SocketAddr,
     last_seen: Instant,
+    /// When it was first heard from.
+    found: Instant,
+    origin: Origin,
+    /// From the peer's pings. None for peers that haven't sent one or are too old to include it.
+    instance: Option<u64>,
+    /// From the peer's pings. Not trusted until the peer proves it over a connection.
+    claimed: Option<Fingerprint>,
+    /// Of the key that the peer proved it has.
+    identity: Option<Fingerprint>,
+    /// Whether a connection was attempted just to check the identity, so that it's only tried once.
+    dialed: bool,
+    /// From the peer's pings.
+    hostname: Option<String>,
+    rooms: Vec<String>,
+    presence: Option<Presence>,
+    note: String,
+}
+
+/// A connection and this side's part of the identity handshake over it.
+struct Link {
+    connection: Connection,
+    /// Sent in this side's hello for the other side to sign. None until the hello is sent.
+    challenge: Option<[u8; CHALLENGE_LEN]>,
+    /// Whether the peer takes envelopes, because it sent one or its pings have a fingerprint.
+    /// Older builds get bare text instead.
+    understood: bool,
+}
+
+/// The open connections, what's needed to set up new ones and what's let through them.
+struct Links {
+    list: Vec<Link>,
+    identity: Identity,
+    /// For the tasks that receive messages to pass them along to this one.
+    to_p2p: TSender<(SocketAddr, Received)>,
+    gate: Gate,
+    /// Sent messages that can still get receipts: the peer, the envelope id and the UI's id.
+    awaiting: Vec<(SocketAddr, u64, u32)>,
+    editable: Vec<Editable>,
+    /// Of the endpoints, for checking proofs. None until they're made.
+    ports: Option<Ports>,
+}
+
+/// A sent message that can still be edited or retracted.
+struct Editable {
+    /// The UI's id.
+    message_id: u32,
+    /// Of the envelope, the same for every peer that got it.
+    id: u64,
+    addresses: Vec<SocketAddr>,
+    /// Who it went to, as written in the log.
+    to: String,
+}
+
+/// What the tasks receiving from connections pass along.
+enum Received {
+    Envelope(Envelope),
+    /// Typed text from a build from before envelopes.
+    Legacy(String),
+    /// The connection with this id was closed.
+    Closed(usize),
+}
+
+/// A typed message on its way to the UI.
+struct Text {
+    content: String,
+    /// For sending receipts back. None for peers too old to send them.
+    receipt: Option<u64>,
+    group: Option<Group>,
+}
+
+/// Decides whose messages get through.
+#[derive(Default)]
+struct Gate {
+    policy: Policy,
+    /// Messages waiting for the user to accept or reject their senders.
+    held: Vec<(SocketAddr, Text)>,
+}
+
+impl Gate {
+    fn allows(&self, peers: &[PeerKnown], address: SocketAddr) -> bool {
+        if !self.policy.approved_only {
+            return true;
+        }
+        let found = peers.iter().find(|r| r.address == address);
+        found.and_then(|a| a.identity)
+            .is_some_and(|a| self.policy.approved.contains(&to_hex(&a)))
+    }
+
+    /// Also goes by the fingerprint in the peer's pings, since a peer that hasn't proven that it
+    /// has the key can only be trying to pass for a blocked one.
+    fn blocks(&self, peers: &[PeerKnown], address: SocketAddr) -> bool {
+        let found = peers.iter().find(|r| r.address == address);
+        self.blocks_peer(address, found.and_then(|a| a.identity.or(a.claimed)))
+    }
+
+    fn blocks_peer(&self, address: SocketAddr, fingerprint: Option<Fingerprint>) -> bool {
+        self.policy.blocked.contains(&peer_key(None, address))
+            || fingerprint.is_some_and(|a| self.policy.blocked.contains(&to_hex(&a)))
+    }
 }
 
-pub async fn task_p2p(from_app: Receiver<ToNet>, mut to_app: Sender<FromNet>,
-        mut to_log: TSender<ToLog>,
-        send_port: WSender<Option<u16>>, mut receive_peer: TReceiver<(SocketAddr, String)>) {
+impl Links {
+    fn find(&self, address: SocketAddr) -> Option<&Connection> {
+        self.list.iter()
+            .find(|r| r.connection.remote_address() == address)
+            .map(|a| &a.connection)
+    }
+
+    /// Whether the address is this computer's end of a connection: one of its own IPs on the port
+    /// of the endpoint for that IP version.
+    fn is_own(&self, address: SocketAddr) -> bool {
+        let port = match (self.ports, address) {
+            (Some(ports), SocketAddr::V4(_)) => Some(ports.v4),
+            (Some(ports), SocketAddr::V6(_)) => ports.v6,
+            (None, _) => None,
+        };
+        if port != Some(address.port()) {
+            return false;
+        }
+        match get_if_addrs() {
+            Ok(interfaces) => interfaces.iter().any(|a| a.ip() == address.ip()),
+            Err(_) => false,
+        }
+    }
+
+    /// Whether the peer's connection takes envelopes.
+    fn understands(&self, address: SocketAddr) -> bool {
+        self.list.iter().any(|r| r.connection.remote_address() == address && r.understood)
+    }
+
+    /// Marks the peer's connection as taking envelopes and starts the handshake over it.
+    fn understood(&mut self, address: SocketAddr) {
+        let found = self.list.iter_mut().find(|r| r.connection.remote_address() == address);
+        if let Some(link) = found {
+            link.understood = true;
+        }
+        self.greet(address);
+    }
+
+    /// Peers that are too old to take changes are left out.
+    fn add_editable(&mut self, mut editable: Editable) {
+        editable.addresses.retain(|a| self.understands(*a));
+        if editable.addresses.is_empty() {
+            return;
+        }
+        if self.editable.len() >= MAX_EDITABLE {
+            self.editable.remove(0);
+        }
+        self.editable.push(editable);
+    }
+
+    /// Closes the connections to blocked peers.
+    fn cut_blocked(&mut self, peers: &[PeerKnown]) {
+        let gate = &self.gate;
+        self.list.retain(|r| {
+            let blocked = gate.blocks(peers, r.connection.remote_address());
+            if blocked {
+                r.connection.close(None);
+            }
+            !blocked
+        });
+    }
+
+    /// Starts the identity handshake unless it already was.
+    fn greet(&mut self, address: SocketAddr) {
+        let found = self.list.iter_mut().find(|r| r.connection.remote_address() == address);
+        if let Some(link) = found {
+            if link.challenge.is_none() {
+                let challenge = random();
+                link.challenge = Some(challenge);
+                let hello = Control::Hello {challenge, seen_at: address};
+                send_control(link.connection.clone(), self.identity.fingerprint, hello);
+            }
+        }
+    }
+}
+
+type Dialed = Result<(Connection, ConnectionIncoming), String>;
+
+/// A new connection to a peer that files were being sent to.
+type Redialed = (SocketAddr, Dialed);
+
+/// A peer added by hand that hasn't been reached yet.
+struct ManualPeer {
+    /// `host` or `host:port` as the user wrote it.
+    spec: String,
+    connecting: bool,
+}
+
+type Reached = (String, Result<(SocketAddr, Connection, ConnectionIncoming), String>);
+
+struct ManualPeers {
+    pending: Vec<ManualPeer>,
+    /// Kept to start trying them again if they leave.
+    reached: Vec<(SocketAddr, String)>,
+    /// For the tasks that try to reach them to report back.
+    to_p2p: TSender<Reached>,
+}
+
+impl ManualPeers {
+    fn add(&mut self, nodes: &Nodes, spec: String) {
+        if !self.pending.iter().any(|a| a.spec == spec) {
+            self.pending.push(ManualPeer {spec: spec.clone(), connecting: true});
+            spawn(task_reach(nodes.clone(), spec, self.to_p2p.clone()));
+        }
+    }
+
+    /// Tries again on the ones that failed.
+    fn retry(&mut self, nodes: &Nodes) {
+        for peer in self.pending.iter_mut().filter(|a| !a.connecting) {
+            peer.connecting = true;
+            spawn(task_reach(nodes.clone(), peer.spec.clone(), self.to_p2p.clone()));
+        }
+    }
+
+    fn reach(&mut self, spec: String, address: SocketAddr) {
+        self.pending.retain(|a| a.spec != spec);
+        self.reached.retain(|a| a.0 != address);
+        self.reached.push((address, spec));
+    }
+
+    /// Goes back to waiting for it to come back.
+    fn leave(&mut self, address: SocketAddr) {
+        if let Some(index) = self.reached.iter().position(|a| a.0 == address) {
+            let (_, spec) = self.reached.remove(index);
+            self.pending.push(ManualPeer {spec, connecting: false});
+        }
+    }
+}
+
+/// How this end of the connections is set up.
+pub struct Setup {
+    /// Where to listen for connections.
+    pub port: u16,
+    /// Peers to connect to without discovery.
+    pub manual: Vec<String>,
+    pub identity: Identity,
+    /// Whether to hold messages from peers that aren't approved until the list of approved ones
+    /// arrives from the UI.
+    pub approved_only: bool,
+    /// For passing changes to the rooms and presence on to discovery.
+    pub profile: WSender<Profile>,
+    /// Counts the peers that are turned away.
+    pub rejected: Arc<Rejected>,
+}
+
+pub async fn task_p2p(setup: Setup,
+        from_app: Receiver<ToNet>, mut to_app: Sender<FromNet>, mut to_log: TSender<ToLog>,
+        send_port: WSender<Option<Ports>>, mut receive_peer: TReceiver<(SocketAddr, Ping)>) {
+    let Setup {port, manual, identity, approved_only, profile, rejected} = setup;
 
     let mut peers_known = Vec::<PeerKnown>::new();
+    let (send_reached, mut receive_reached) = channel::<Reached>(1);
+    let (send_dialed, mut receive_dialed) = channel::<Dialed>(1);
+    let (send_redialed, mut receive_redialed) = channel::<Redialed>(1);
+    let (send_received, mut receive_received) = channel(1);
+    let (send_sent, mut receive_sent) = channel::<Sent>(1);
+    let mut links = Links {
+        list: vec![],
+        identity,
+        to_p2p: send_received,
+        gate: Gate {
+            policy: Policy {approved_only, ..Default::default()},
+            ..Default::default()
+        },
+        awaiting: vec![],
+        editable: vec![],
+        ports: None,
+    };
+    let mut manual = ManualPeers {
+        pending: manual.into_iter()
+            .map(|spec| ManualPeer {spec, connecting: false})
+            .collect(),
+        reached: vec![],
+        to_p2p: send_reached,
+    };
+    // Instance ids of peers that said they're leaving, so that a ping that was already on its way
+    // doesn't bring them back.
+    let mut departed = Vec::<(u64, Instant)>::new();
+    let mut files = Transfers::new(send_sent, links.identity.fingerprint);
     let mut commands = pull_commands(from_app);
     'restart: loop {
         // TODO: maybe wait until a remote peer is discovered before building the endpoint
 
-        if let Err(_) = send_port.send(None) {
+        if send_port.send(None).is_err() {
             return;
         }
         
-        let ep = Endpoint::new_peer(
-            SocketAddr::from(([0, 0, 0, 0], 0)),
-            &peers_known
-                .iter().map(|r| r.address).collect::<Vec<_>>(),
-            Config {
-                idle_timeout: Some(Duration::from_secs(60 * 5)),
-                ..Default::default()
-            },
-        ).await;
+        let ep = make_endpoint(SocketAddr::from(([0, 0, 0, 0], port)), &peers_known).await;
 
         let (node, mut incoming_conns, _contact) = match ep {
             Ok(a) => a,
@@ -53,40 +381,262 @@ pub async fn task_p2p(from_app: Receiver<ToNet>, mut to_app: Sender<FromNet>,
                 continue;
             }
         };
-        let port = node.public_addr().port();
-        if let Err(_) = send_port.send(Some(port)) {
+
+        // IPv6 is optional. Machines without it still work over IPv4.
+        let ep6 = make_endpoint(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)), &peers_known)
+            .await;
+        let (node6, mut incoming_conns6) = match ep6 {
+            Ok((node6, incoming6, _contact)) => (Some(node6), Some(incoming6)),
+            Err(_) => (None, None),
+        };
+        let nodes = Nodes {v4: node, v6: node6};
+
+        let ports = Ports {
+            v4: nodes.v4.public_addr().port(),
+            v6: nodes.v6.as_ref().map(|a| a.public_addr().port()),
+        };
+        links.ports = Some(ports);
+        if send_port.send(Some(ports)).is_err() {
             return;
         }
 
         let mut interval = interval(Duration::from_secs(5));
         interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
-        let mut connections = Vec::<Connection>::new();
+        links.list.clear();
         loop {
             select! {
                 now = interval.tick() => {
                     cull_peers(&mut to_app, &mut peers_known, now);
+                    departed.retain(|r| now.duration_since(r.1) < PEER_IDLE_TIME);
+
+                    manual.retry(&nodes);
+
+                    check_identities(&nodes, &mut links, &mut peers_known, &send_dialed);
+
+                    resume_files(&nodes, &peers_known, &mut files, &send_redialed).await;
+                    if !files.cull(&mut to_app, now).await {
+                        return;
+                    }
                 }
 
                 command = commands.recv() => {
-                    let command = if let Some(a) = command {
+                    let command = match command {
+                        Some(ToNet::Leave) | None => {
+                            files.discard_all().await;
+                            say_goodbye(&links).await;
+                            return;
+                        }
+                        Some(a) => a,
+                    };
+
+                    match command {
+                        ToNet::SetRooms(rooms) => {
+                            leave_rooms(&mut to_app, &mut peers_known, &rooms);
+                            let next = Profile {rooms, ..profile.borrow().clone()};
+                            profile.send_replace(next);
+                            continue;
+                        }
+                        ToNet::SetName(name) => {
+                            profile.send_replace(Profile {name, ..profile.borrow().clone()});
+                            continue;
+                        }
+                        ToNet::SetPresence {presence, note} => {
+                            let next = Profile {presence, note, ..profile.borrow().clone()};
+                            profile.send_replace(next);
+                            continue;
+                        }
+                        ToNet::SendFile {id, address, path} => {
+                            let connection = match file_link(&nodes, &mut links, &peers_known,
+                                    address).await {
+                                Ok(a) => a,
+                                Err(error) => {
+                                    if to_app.send(FromNet::FileFailed {id, error}).is_err() {
+                                        return;
+                                    }
+                                    continue;
+                                }
+                            };
+                            if !files.offer(&mut to_app, connection, id, path).await {
+                                return;
+                            }
+                            continue;
+                        }
+                        ToNet::AcceptFile(id) => {
+                            if !files.accept(&mut to_app, id).await {
+                                return;
+                            }
+                            continue;
+                        }
+                        ToNet::CancelFile(id) => {
+                            files.cancel(id).await;
+                            continue;
+                        }
+                        _ => {}
+                    }
+                    on_command(&mut to_app, &mut to_log, &nodes, &mut links, &peers_known,
+                        &mut manual, command).await;
+                }
+
+                reached = receive_reached.recv() => {
+                    let (spec, result) = if let Some(a) = reached {
+                        a
+                    } else {
+                        return;
+                    };
+
+                    match result {
+                        Ok((address, connection, incoming_messages)) => {
+                            let name = split_host(&spec).0;
+                            manual.reach(spec, address);
+                            if !on_new_peer(&mut to_app, &mut peers_known, address, name,
+                                    Origin::Manual) {
+                                return;
+                            }
+                            on_connection(&mut links, &peers_known, connection, incoming_messages);
+                        }
+                        Err(error) => {
+                            if let Some(peer) = manual.pending.iter_mut().find(|a| a.spec == spec) {
+                                peer.connecting = false;
+                            }
+                            if !show_status(&mut to_app, format!("can't reach {}: {}", spec, error)) {
+                                return;
+                            }
+                        }
+                    }
+                }
+
+                sent = receive_sent.recv() => {
+                    let sent = if let Some(a) = sent {
+                        a
+                    } else {
+                        return;
+                    };
+
+                    if !files.sent(&mut to_app, sent) {
+                        return;
+                    }
+                }
+
+                dialed = receive_dialed.recv() => {
+                    let result = if let Some(a) = dialed {
+                        a
+                    } else {
+                        return;
+                    };
+
+                    // Failing is fine. The peer just stays unconfirmed.
+                    if let Ok((connection, incoming_messages)) = result {
+                        on_connection(&mut links, &peers_known, connection, incoming_messages);
+                    }
+                }
+
+                redialed = receive_redialed.recv() => {
+                    let (address, result) = if let Some(a) = redialed {
+                        a
+                    } else {
+                        return;
+                    };
+
+                    let connection = match result {
+                        Ok((connection, incoming_messages)) => {
+                            on_connection(&mut links, &peers_known, connection.clone(),
+                                incoming_messages);
+                            Some(connection)
+                        }
+                        Err(_) => None,
+                    };
+                    files.resume(address, connection).await;
+                }
+
+                received = receive_received.recv() => {
+                    let (address, received) = if let Some(a) = received {
                         a
                     } else {
                         return;
                     };
 
-                    on_command(&mut to_app, &mut to_log, &node, &mut connections, &peers_known,
-                        command).await;
+                    let (kind, id) = match received {
+                        Received::Envelope(envelope) => {
+                            if !sent_by(&peers_known, address, &envelope) {
+                                continue;
+                            }
+                            links.understood(address);
+                            (envelope.kind, Some(envelope.id))
+                        }
+                        Received::Legacy(content) => (Kind::Text(content), None),
+                        Received::Closed(id) => {
+                            if !on_closed(&mut to_app, &mut links, &mut peers_known, address, id) {
+                                return;
+                            }
+                            continue;
+                        }
+                    };
+                    let connected = match kind {
+                        Kind::Control(Control::Leave) => {
+                            on_leave(&mut to_app, &mut links, &mut peers_known, &mut manual,
+                                &mut departed, address, None)
+                        }
+                        Kind::Control(Control::File(control)) => {
+                            on_file(&mut to_app, &links, &peers_known, &mut files, address,
+                                control).await
+                        }
+                        Kind::Control(control) => {
+                            on_control(&mut to_app, &mut links, &mut peers_known, address, control)
+                        }
+                        Kind::Text(content) => {
+                            let text = Text {content, receipt: id, group: None};
+                            on_text(&mut to_app, &mut to_log, &mut links.gate, &peers_known,
+                                address, text).await
+                        }
+                        Kind::GroupText {group, content} => {
+                            // Only members with proven identities can send to groups.
+                            if !is_proven(&peers_known, address) {
+                                continue;
+                            }
+                            let text = Text {content, receipt: id, group: Some(group)};
+                            on_text(&mut to_app, &mut to_log, &mut links.gate, &peers_known,
+                                address, text).await
+                        }
+                        Kind::Group(group) => {
+                            on_group(&mut to_app, &links.gate, &peers_known, address, group)
+                        }
+                        Kind::Receipt {id, read} => {
+                            on_receipt(&mut to_app, &mut links, address, id, read)
+                        }
+                        Kind::Typing => on_typing(&mut to_app, &links.gate, &peers_known, address),
+                        Kind::Edit {id, content} => {
+                            on_edit(&mut to_app, &mut to_log, &mut links.gate, &peers_known,
+                                address, id, Some(content)).await
+                        }
+                        Kind::Retract {id} => {
+                            on_edit(&mut to_app, &mut to_log, &mut links.gate, &peers_known,
+                                address, id, None).await
+                        }
+                    };
+                    if !connected {
+                        return;
+                    }
                 }
 
                 peer = receive_peer.recv() => {
-                    let (address, name) = if let Some(a) = peer {
+                    let (address, ping) = if let Some(a) = peer {
                         a
                     } else {
                         return;
                     };
 
-                    on_peer(&mut peers_known, address, name);
+                    let connected = if ping.leaving {
+                        on_leave_ping(&mut to_app, &mut links, &mut peers_known, &mut manual,
+                            &mut departed, address, ping.instance)
+                    } else if ping.instance.is_some_and(|a| departed.iter().any(|r| r.0 == a)) {
+                        true
+                    } else {
+                        on_peer(&mut to_app, &mut peers_known, &rejected, address, ping)
+                    };
+                    if !connected {
+                        return;
+                    }
                 }
 
                 arrival = incoming_conns.next() => {
@@ -97,34 +647,178 @@ pub async fn task_p2p(from_app: Receiver<ToNet>, mut to_app: Sender<FromNet>,
                         continue 'restart;
                     };
 
-                    on_connection(to_app.clone(), to_log.clone(),
-                        &mut connections, &peers_known, connection, incoming_messages);
+                    let address = connection.remote_address();
+                    if !peers_known.iter().any(|r| r.address == address) {
+                        if peers_known.len() >= MAX_PEERS {
+                            rejected.count(&rejected.too_many, address.ip());
+                            connection.close(None);
+                            continue;
+                        }
+                        if !on_new_peer(&mut to_app, &mut peers_known, address,
+                                address.ip().to_string(), Origin::Incoming) {
+                            return;
+                        }
+                    }
+                    on_connection(&mut links, &peers_known, connection, incoming_messages);
+                }
+
+                arrival = next_incoming(&mut incoming_conns6) => {
+                    let (connection, incoming_messages) = if let Some(a) = arrival {
+                        a
+                    } else {
+                        sleep(Duration::from_secs(5)).await;
+                        continue 'restart;
+                    };
+
+                    let address = connection.remote_address();
+                    if !peers_known.iter().any(|r| r.address == address) {
+                        if peers_known.len() >= MAX_PEERS {
+                            rejected.count(&rejected.too_many, address.ip());
+                            connection.close(None);
+                            continue;
+                        }
+                        if !on_new_peer(&mut to_app, &mut peers_known, address,
+                                address.ip().to_string(), Origin::Incoming) {
+                            return;
+                        }
+                    }
+                    on_connection(&mut links, &peers_known, connection, incoming_messages);
                 }
             }
         }
     }
 }
 
-async fn task_receive_one(to_app: Sender<FromNet>, to_log: TSender<ToLog>,
-        source: IpAddr, name: String, mut incoming: ConnectionIncoming) {
+type NewEndpoint = (Endpoint, IncomingConnections, Option<(Connection, ConnectionIncoming)>);
+
+/// Falls back on a random port if the requested one is taken, e.g. by another instance.
+async fn make_endpoint(local: SocketAddr, peers_known: &[PeerKnown])
+        -> Result<NewEndpoint, qp2p::EndpointError> {
+    let result = make_endpoint_on(local, peers_known).await;
+    if result.is_err() && local.port() != 0 {
+        let mut local = local;
+        local.set_port(0);
+        return make_endpoint_on(local, peers_known).await;
+    }
+    result
+}
+
+async fn make_endpoint_on(local: SocketAddr, peers_known: &[PeerKnown])
+        -> Result<NewEndpoint, qp2p::EndpointError> {
+    Endpoint::new_peer(
+        local,
+        &peers_known
+            .iter().map(|r| r.address)
+            .filter(|a| a.is_ipv4() == local.is_ipv4())
+            .collect::<Vec<_>>(),
+        Config {
+            idle_timeout: Some(Duration::from_secs(60 * 5)),
+            ..Default::default()
+        },
+    ).await
+}
+
+/// Never resolves if there is no endpoint to listen on.
+async fn next_incoming(incoming: &mut Option<IncomingConnections>)
+        -> Option<(Connection, ConnectionIncoming)> {
+    match incoming {
+        Some(incoming) => incoming.next().await,
+        None => pending().await,
+    }
+}
+
+async fn task_receive_one(to_p2p: TSender<(SocketAddr, Received)>, source: SocketAddr, id: usize,
+        mut incoming: ConnectionIncoming) {
     while let Ok(obytes) = incoming.next().await {
         let bytes = if let Some(a) = obytes {
             a
         } else {
-            return;
+            break;
         };
 
-        let content = String::from_utf8_lossy(&bytes).into_owned();
-        
-        if let Err(_) = to_log.send(ToLog::LogMessage(
-            format!("\nfrom [{}] [{}] {}", name, source.to_string(), content.clone())
-        )).await {
+        let received = if is_envelope(&bytes) {
+            match open(&bytes) {
+                Some(envelope) => Received::Envelope(envelope),
+                None => continue,
+            }
+        } else {
+            Received::Legacy(String::from_utf8_lossy(&bytes).into_owned())
+        };
+        if to_p2p.send((source, received)).await.is_err() {
             return;
         }
-        if let Err(_) = to_app.send(FromNet::ShowMessage {source, content}) {
-            return;
+    }
+    let _ = to_p2p.send((source, Received::Closed(id))).await;
+}
+
+/// false = disconnected
+#[must_use]
+async fn on_text(to_app: &mut Sender<FromNet>, to_log: &mut TSender<ToLog>, gate: &mut Gate,
+        peers: &[PeerKnown], source: SocketAddr, text: Text) -> bool {
+    if gate.blocks(peers, source) {
+        return true;
+    }
+    if gate.allows(peers, source) {
+        return deliver(to_app, to_log, peers, source, text).await;
+    }
+
+    if gate.held.len() < MAX_HELD {
+        gate.held.push((source, text));
+    }
+    let count = gate.held.iter().filter(|r| r.0 == source).count();
+    if to_app.send(FromNet::Request {address: source, count}).is_err() {
+        return false;
+    }
+    true
+}
+
+/// Logs and shows a received message.
+/// false = disconnected
+#[must_use]
+async fn deliver(to_app: &mut Sender<FromNet>, to_log: &mut TSender<ToLog>, peers: &[PeerKnown],
+        source: SocketAddr, text: Text) -> bool {
+    let Text {content, receipt, group} = text;
+    let found = peers.iter().find(|r| r.address == source);
+    let name = found.map(|a| a.name.clone()).unwrap_or(source.to_string());
+
+    let place = group.as_ref().map(|a| format!(" in [{}]", a.name)).unwrap_or_default();
+    if to_log.send(ToLog::LogMessage(
+        format!("\nfrom [{}]{} [{}] {}", name, place, source, content.clone())
+    )).await.is_err() {
+        return false;
+    }
+    if to_app.send(FromNet::ShowMessage {source, content, receipt, group}).is_err() {
+        return false;
+    }
+    true
+}
+
+/// Delivers the held messages that are now allowed through, along with the ones from the peer
+/// that the user accepted them from if any, and drops the ones from peers that are now blocked.
+/// false = disconnected
+#[must_use]
+async fn release(to_app: &mut Sender<FromNet>, to_log: &mut TSender<ToLog>, gate: &mut Gate,
+        peers: &[PeerKnown], accepted: Option<SocketAddr>) -> bool {
+    let (decided, held): (Vec<_>, Vec<_>) = take(&mut gate.held).into_iter()
+        .partition(|(source, _)| accepted == Some(*source) || gate.allows(peers, *source)
+            || gate.blocks(peers, *source));
+    gate.held = held;
+
+    let mut sources = decided.iter().map(|r| r.0).collect::<Vec<_>>();
+    sources.sort();
+    sources.dedup();
+    let allowed = decided.into_iter().filter(|(source, _)| !gate.blocks(peers, *source));
+    for (source, text) in allowed {
+        if !deliver(to_app, to_log, peers, source, text).await {
+            return false;
+        }
+    }
+    for address in sources {
+        if to_app.send(FromNet::Request {address, count: 0}).is_err() {
+            return false;
         }
     }
+    true
 }
 
 fn pull_commands(from_app: Receiver<ToNet>) -> TReceiver<ToNet> {
@@ -136,7 +830,7 @@ fn pull_commands(from_app: Receiver<ToNet>) -> TReceiver<ToNet> {
         while let Ok(command) = from_app.recv() {
             let f = to_outer.send(command);
 
-            if let Err(_) = Handle::current().block_on(f) {
+            if Handle::current().block_on(f).is_err() {
                 return;
             }
         }
@@ -145,50 +839,238 @@ fn pull_commands(from_app: Receiver<ToNet>) -> TReceiver<ToNet> {
     commands
 }
 
-async fn send_message(connections: &mut Vec<Connection>,
-        address: IpAddr, content: String) -> Result<(), String> {
+/// In an envelope if the peer understands them, or as bare text for older builds. Returns the id
+/// of the envelope if there was one.
+fn wrap_text(sender: Fingerprint, link: &Link, content: String) -> (Option<u64>, Vec<u8>) {
+    if link.understood {
+        let id = random();
+        (Some(id), seal_with_id(id, sender, Kind::Text(content)))
+    } else {
+        (None, content.into_bytes())
+    }
+}
 
-    let found = connections
-        .iter().find(|r| r.remote_address().ip() == address);
+/// Returns Result<id of the envelope if there was one, description of failure>
+async fn send_message(links: &Links,
+        address: SocketAddr, content: String) -> Result<Option<u64>, String> {
+
+    let found = links.list.iter().find(|r| r.connection.remote_address() == address);
     let dest = found.ok_or(format!("no connection to {}", address))?;
-    dest.send(content.into()).await
-        .map_err(|a| a.to_string())
+    let (id, bytes) = wrap_text(links.identity.fingerprint, dest, content);
+    dest.connection.send(bytes.into()).await
+        .map_err(|a| a.to_string())?;
+    Ok(id)
 }
 
-/// Returns Result<name of peer, description of failure>
-async fn send_twice(to_app: &mut Sender<FromNet>, to_log: &mut TSender<ToLog>,
-        node: &Endpoint, connections: &mut Vec<Connection>, peers: &[PeerKnown],
-        address: IpAddr, content: String) -> Result<String, String> {
-    let found = peers.iter().find(|r| r.address.ip() == address);
+/// Sends the same message to several peers at once, then reconnects to the ones that it didn't
+/// get through to, all at once too, and tries them again. Kinds other than text only go to peers
+/// that understand envelopes. Every peer gets the same envelope id.
+/// Returns (peers it was sent to, peers it couldn't be sent to)
+async fn fan_out(nodes: &Nodes, links: &mut Links, peers: &[PeerKnown],
+        addresses: Vec<SocketAddr>, id: u64, kind: Kind) -> (Vec<SocketAddr>, Vec<SocketAddr>) {
+    let legacy = match &kind {
+        Kind::Text(content) => Some(content.clone().into_bytes()),
+        _ => None,
+    };
+    let sealed = seal_with_id(id, links.identity.fingerprint, kind);
+    let bytes_for = |link: &Link| if link.understood {
+        Some(sealed.clone())
+    } else {
+        legacy.clone()
+    };
+
+    let mut ready = vec![];
+    let mut retry = vec![];
+    for address in addresses {
+        let found = links.list.iter().find(|r| r.connection.remote_address() == address);
+        match found.and_then(|a| bytes_for(a).map(|b| (a.connection.clone(), b))) {
+            Some((connection, bytes)) => ready.push((address, connection, bytes)),
+            None => retry.push(address),
+        }
+    }
+    let (mut sent, unsent) = send_each(ready).await;
+    retry.extend(unsent);
+
+    // So that a peer that's gone doesn't hold up the others.
+    let dialing: Vec<_> = retry.into_iter()
+        .map(|address| {
+            let found = peers.iter().find(|r| r.address == address);
+            let node = found.and_then(|a| nodes.for_address(&a.address)).cloned();
+            (address, spawn(async move {
+                node?.connect_to(&address).await.ok()
+            }))
+        })
+        .collect();
+    let mut ready = vec![];
+    let mut failed = vec![];
+    for (address, task) in dialing {
+        let bytes = if let Ok(Some((connection, incoming_messages))) = task.await {
+            on_connection(links, peers, connection, incoming_messages);
+            links.list.iter()
+                .find(|r| r.connection.remote_address() == address)
+                .and_then(|a| bytes_for(a).map(|b| (a.connection.clone(), b)))
+        } else {
+            None
+        };
+        match bytes {
+            Some((connection, bytes)) => ready.push((address, connection, bytes)),
+            None => failed.push(address),
+        }
+    }
+    let (resent, unsent) = send_each(ready).await;
+    sent.extend(resent);
+    failed.extend(unsent);
+    (sent, failed)
+}
+
+/// Sends over every connection at once.
+/// Returns (peers it was sent to, peers it couldn't be sent to)
+async fn send_each(sends: Vec<(SocketAddr, Connection, Vec<u8>)>)
+        -> (Vec<SocketAddr>, Vec<SocketAddr>) {
+    let tasks: Vec<_> = sends.into_iter()
+        .map(|(address, connection, bytes)| {
+            (address, spawn(async move {
+                connection.send(bytes.into()).await.is_ok()
+            }))
+        })
+        .collect();
+
+    let mut sent = vec![];
+    let mut failed = vec![];
+    for (address, task) in tasks {
+        match task.await {
+            Ok(true) => sent.push(address),
+            _ => failed.push(address),
+        }
+    }
+    (sent, failed)
+}
+
+/// Names the peers that something sent to several of them didn't reach, if any.
+/// false = disconnected
+#[must_use]
+fn show_unreached(to_app: &mut Sender<FromNet>, peers: &[PeerKnown], unreached: &[SocketAddr],
+        what: &str) -> bool {
+    if unreached.is_empty() {
+        return true;
+    }
+    let names: Vec<_> = unreached.iter()
+        .map(|address| match peers.iter().find(|r| r.address == *address) {
+            Some(peer) => format!("{} [{}]", peer.name, address),
+            None => address.to_string(),
+        })
+        .collect();
+    show_error(to_app, format!("error: {} didn't reach {}", what, names.join(", ")))
+}
+
+/// The addresses of the peers that aren't blocked, of the ones with these identities if given.
+fn addresses_of(links: &Links, peers: &[PeerKnown], identities: Option<&[String]>)
+        -> Vec<SocketAddr> {
+    peers.iter()
+        .filter(|r| !links.gate.blocks(peers, r.address))
+        .filter(|r| match identities {
+            Some(list) => r.identity.is_some_and(|a| list.contains(&to_hex(&a))),
+            None => true,
+        })
+        .map(|r| r.address)
+        .collect()
+}
+
+/// Returns Result<(name of peer, id of the envelope if there was one), description of failure>
+async fn send_twice(nodes: &Nodes, links: &mut Links, peers: &[PeerKnown],
+        address: SocketAddr, content: String) -> Result<(String, Option<u64>), String> {
+    let found = peers.iter().find(|r| r.address == address);
     let peer = found.ok_or(format!("no connection to {}", address))?;
     
-    if let Ok(_) = send_message(connections, address, content.clone()).await {
-        return Ok(peer.name.clone());
+    if let Ok(id) = send_message(links, address, content.clone()).await {
+        return Ok((peer.name.clone(), id));
     }
     
-    let (conn, incoming_messages) = node.connect_to(&peer.address).await
-        .map_err(|a| a.to_string())?;
-    on_connection(to_app.clone(), to_log.clone(), connections, peers, conn, incoming_messages);
+    reconnect(nodes, links, peers, address).await?;
     
-    send_message(connections, address, content.clone()).await
+    let id = send_message(links, address, content.clone()).await
         .map_err(|a| a.to_string())?;
         
-    Ok(peer.name.clone())
+    Ok((peer.name.clone(), id))
+}
+
+/// Opens a new connection to the peer in place of the old one, if there was one.
+async fn reconnect(nodes: &Nodes, links: &mut Links, peers: &[PeerKnown],
+        address: SocketAddr) -> Result<Connection, String> {
+    let found = peers.iter().find(|r| r.address == address);
+    let peer = found.ok_or(format!("no connection to {}", address))?;
+
+    let node = nodes.for_address(&peer.address)
+        .ok_or(format!("no IPv6 connectivity for {}", address))?;
+    let (conn, incoming_messages) = node.connect_to(&peer.address).await
+        .map_err(|a| a.to_string())?;
+    on_connection(links, peers, conn.clone(), incoming_messages);
+    Ok(conn)
+}
+
+/// Connects again to the peers that files were being sent to when the connection broke, in the
+/// background so that the rest of the network doesn't wait on it. Peers that can't be reached yet
+/// are tried on a later tick.
+async fn resume_files(nodes: &Nodes, peers: &[PeerKnown], files: &mut Transfers,
+        to_p2p: &TSender<Redialed>) {
+    for address in files.interrupted() {
+        let found = peers.iter().find(|r| r.address == address);
+        match found.and_then(|a| nodes.for_address(&a.address)) {
+            Some(node) => {
+                spawn(task_redial(node.clone(), address, to_p2p.clone()));
+            }
+            None => files.resume(address, None).await,
+        }
+    }
+}
+
+async fn task_redial(node: Endpoint, address: SocketAddr, to_p2p: TSender<Redialed>) {
+    let result = node.connect_to(&address).await
+        .map_err(|a| a.to_string());
+    let _ = to_p2p.send((address, result)).await;
+}
+
+/// Connects to the peer if needed. Files can only be sent to peers that took part in the identity
+/// handshake, since older builds would show the chunks as text.
+async fn file_link(nodes: &Nodes, links: &mut Links, peers: &[PeerKnown],
+        address: SocketAddr) -> Result<Connection, String> {
+    if links.find(address).is_none() {
+        reconnect(nodes, links, peers, address).await?;
+    }
+    let found = links.list.iter().find(|r| r.connection.remote_address() == address);
+    // Pings without a fingerprint come from builds that are too old. Otherwise the peer just
+    // hasn't answered the hello over the new connection yet.
+    let old = peers.iter()
+        .any(|r| r.address == address && r.origin == Origin::Discovered && r.claimed.is_none());
+    match found {
+        Some(link) if link.understood => Ok(link.connection.clone()),
+        Some(_) if old => Err("its version of sclan can't receive files".into()),
+        Some(_) => Err("it hasn't answered the handshake yet, try again in a moment".into()),
+        None => Err(format!("no connection to {}", address)),
+    }
 }
 
-async fn on_command(to_app: &mut Sender<FromNet>, to_log: &mut TSender<ToLog>, node: &Endpoint,
-        connections: &mut Vec<Connection>, peers: &[PeerKnown], command: ToNet) {
+async fn on_command(to_app: &mut Sender<FromNet>, to_log: &mut TSender<ToLog>, nodes: &Nodes,
+        links: &mut Links, peers: &[PeerKnown],
+        manual: &mut ManualPeers, command: ToNet) {
     match command {
         ToNet::Send {message_id, address, content} => {
-            match send_twice(to_app, to_log,
-                    node, connections, peers, address, content.clone()).await {
-                Ok(name) => {
-                    if let Err(_) = to_app.send(FromNet::SendArrived(message_id)) {
+            match send_twice(nodes, links, peers, address, content.clone()).await {
+                Ok((name, id)) => {
+                    if let Some(id) = id {
+                        if links.awaiting.len() >= MAX_AWAITING {
+                            links.awaiting.remove(0);
+                        }
+                        links.awaiting.push((address, id, message_id));
+                        let to = format!("[{}] [{}]", name, address);
+                        links.add_editable(Editable {message_id, id, addresses: vec![address], to});
+                    }
+                    if to_app.send(FromNet::SendArrived(message_id)).is_err() {
                         return;
                     }
-                    if let Err(_) = to_log.send(ToLog::LogMessage(
-                        format!("\nto [{}] [{}] {}", name, address.to_string(), content)
-                    )).await {
+                    if to_log.send(ToLog::LogMessage(
+                        format!("\nto [{}] [{}] {}", name, address, content)
+                    )).await.is_err() {
                         return;
                     }
                 }
@@ -196,66 +1078,641 @@ async fn on_command(to_app: &mut Sender<FromNet>, to_log: &mut TSender<ToLog>, n
                     if !show_error(to_app, format!("error: {:?}", error)) {
                         return;
                     }
-                    if let Err(_) = to_app.send(FromNet::SendFailed(message_id)) {
+                    if to_app.send(FromNet::SendFailed(message_id)).is_err() {
                         return;
                     }
                 }
             }
         }
+        ToNet::Broadcast {message_id, content} => {
+            let addresses = addresses_of(links, peers, None);
+            let kind = Kind::Text(content.clone());
+            let id = random();
+            let (sent, failed) = fan_out(nodes, links, peers, addresses, id, kind).await;
+            let count = sent.len();
+            let to = "[everyone]".into();
+            links.add_editable(Editable {message_id, id, addresses: sent, to});
+            let sent = FromNet::BroadcastSent {message_id, sent: count, failed: failed.len()};
+            if to_app.send(sent).is_err() {
+                return;
+            }
+            if !show_unreached(to_app, peers, &failed, "the message") {
+                return;
+            }
+            if count > 0 && to_log.send(ToLog::LogMessage(
+                format!("\nto [everyone] {}", content)
+            )).await.is_err() {
+                return;
+            }
+        }
+        ToNet::SendGroup {message_id, group, content} => {
+            let addresses = addresses_of(links, peers, Some(&group.members));
+            let name = group.name.clone();
+            let kind = Kind::GroupText {group, content: content.clone()};
+            let id = random();
+            let (sent, failed) = fan_out(nodes, links, peers, addresses, id, kind).await;
+            let count = sent.len();
+            let to = format!("[{}]", name);
+            links.add_editable(Editable {message_id, id, addresses: sent, to});
+            let sent = FromNet::BroadcastSent {message_id, sent: count, failed: failed.len()};
+            if to_app.send(sent).is_err() {
+                return;
+            }
+            if !show_unreached(to_app, peers, &failed, "the message") {
+                return;
+            }
+            if count > 0 && to_log.send(ToLog::LogMessage(
+                format!("\nto [{}] {}", name, content)
+            )).await.is_err() {
+                return;
+            }
+        }
+        ToNet::ShareGroup {group, to} => {
+            // Members that don't get it now get it with the next message to the group.
+            let addresses = addresses_of(links, peers, Some(&to));
+            fan_out(nodes, links, peers, addresses, random(), Kind::Group(group)).await;
+        }
+        ToNet::Edit {message_id, content} => {
+            let found = links.editable.iter().position(|r| r.message_id == message_id);
+            let index = if let Some(a) = found {
+                a
+            } else {
+                return;
+            };
+            let Editable {id, addresses, to, ..} = &links.editable[index];
+            let (id, addresses, to) = (*id, addresses.clone(), to.clone());
+            let (kind, entry) = match content {
+                Some(content) => {
+                    let entry = format!("\nedited message to {} {}", to, content);
+                    (Kind::Edit {id, content}, entry)
+                }
+                None => {
+                    // Nothing more can be done with it.
+                    links.editable.remove(index);
+                    (Kind::Retract {id}, format!("\nretracted message to {}", to))
+                }
+            };
+            let (sent, failed) = fan_out(nodes, links, peers, addresses, random(), kind).await;
+            if !show_unreached(to_app, peers, &failed, "the change") {
+                return;
+            }
+            // Written as a new entry so that the log keeps what was sent first.
+            if !sent.is_empty() && to_log.send(ToLog::LogMessage(entry)).await.is_err() {
+                return;
+            }
+        }
         ToNet::LogStart => {
-            if let Err(_) = to_log.send(ToLog::LogStart).await {
+            if to_log.send(ToLog::LogStart).await.is_err() {
+                return;
+            }
+        }
+        ToNet::AddPeer(spec) => manual.add(nodes, spec),
+        ToNet::SetPolicy(policy) => {
+            links.gate.policy = policy;
+            links.cut_blocked(peers);
+            if !release(to_app, to_log, &mut links.gate, peers, None).await {
+                return;
+            }
+        }
+        ToNet::Accept(address) => {
+            if !release(to_app, to_log, &mut links.gate, peers, Some(address)).await {
                 return;
             }
         }
+        ToNet::Reject(address) => {
+            links.gate.held.retain(|r| r.0 != address);
+        }
+        ToNet::Receipt {address, id, read} => {
+            if let Some(connection) = links.find(address) {
+                let receipt = Kind::Receipt {id, read};
+                send_kind(connection.clone(), links.identity.fingerprint, receipt);
+            }
+        }
+        ToNet::Typing(address) => {
+            let found = links.list.iter().find(|r| r.connection.remote_address() == address);
+            if let Some(link) = found.filter(|a| a.understood) {
+                send_kind(link.connection.clone(), links.identity.fingerprint, Kind::Typing);
+            }
+        }
+        // handled by task_p2p
+        ToNet::Leave | ToNet::SetRooms(_) | ToNet::SetName(_) | ToNet::SetPresence {..}
+        | ToNet::SendFile {..} | ToNet::AcceptFile(_) | ToNet::CancelFile(_) => {}
     }
 }
 
+/// Forgets the discovered peers that aren't in any of the rooms anymore.
+fn leave_rooms(to_app: &mut Sender<FromNet>, peers_known: &mut Vec<PeerKnown>, rooms: &[String]) {
+    peers_known.retain(|peer| {
+        if peer.origin != Origin::Discovered || shares_room(rooms, &peer.rooms) {
+            true
+        } else {
+            let _ = to_app.send(FromNet::Peerbgone(peer.address));
+            false
+        }
+    });
+}
+
 fn cull_peers(to_app: &mut Sender<FromNet>, peers_known: &mut Vec<PeerKnown>, now: Instant) {
     peers_known.retain(|peer| {
-        if now.duration_since(peer.last_seen) < PEER_IDLE_TIME {
+        if peer.origin != Origin::Discovered
+        || now.duration_since(peer.last_seen) < PEER_IDLE_TIME {
             true
         } else {
-            let _ = to_app.send(FromNet::Peerbgone(peer.address.ip()));
+            let _ = to_app.send(FromNet::Peerbgone(peer.address));
             false
         }
     });
 }
 
-fn on_peer(peers_known: &mut Vec<PeerKnown>, address: SocketAddr, name: String) {
-    let ip = address.ip();
+/// Forgets the link once its connection is closed, along with the peer if all that was known of
+/// it was that it connected. Discovered peers are left to their pings.
+/// false = disconnected
+#[must_use]
+fn on_closed(to_app: &mut Sender<FromNet>, links: &mut Links, peers_known: &mut Vec<PeerKnown>,
+        address: SocketAddr, id: usize) -> bool {
+    // A newer connection to the same address may have taken its place already.
+    links.list.retain(|r| r.connection.remote_address() != address || r.connection.id() != id);
+    if links.find(address).is_some() {
+        return true;
+    }
+    let found = peers_known.iter()
+        .position(|r| r.address == address && r.origin == Origin::Incoming);
+    if let Some(index) = found {
+        peers_known.remove(index);
+        if to_app.send(FromNet::Peerbgone(address)).is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+/// Forgets a peer that said it's leaving.
+/// false = disconnected
+#[must_use]
+fn on_leave(to_app: &mut Sender<FromNet>, links: &mut Links, peers_known: &mut Vec<PeerKnown>,
+        manual: &mut ManualPeers, departed: &mut Vec<(u64, Instant)>, address: SocketAddr,
+        instance: Option<u64>) -> bool {
+    let found = peers_known.iter().position(|r| r.address == address
+        || (instance.is_some() && r.instance == instance));
+    let peer = found.map(|index| peers_known.remove(index));
+
+    if let Some(instance) = instance.or(peer.as_ref().and_then(|a| a.instance)) {
+        departed.push((instance, Instant::now()));
+    }
+    let peer = if let Some(a) = peer {
+        a
+    } else {
+        return true;
+    };
 
-    if let Some(index) = peers_known
-            .iter().position(|r| r.address.ip() == ip) {
+    links.list.retain(|r| r.connection.remote_address() != peer.address);
+    if peer.origin == Origin::Manual {
+        manual.leave(peer.address);
+    }
+    if to_app.send(FromNet::Peerbgone(peer.address)).is_err() {
+        return false;
+    }
+    true
+}
+
+/// Pings can be forged, so saying it's leaving in one only counts for the peer at the address that
+/// it came from, and not for a peer that proved its identity. Those say so over the connection or
+/// are forgotten once their pings stop.
+/// false = disconnected
+#[must_use]
+fn on_leave_ping(to_app: &mut Sender<FromNet>, links: &mut Links,
+        peers_known: &mut Vec<PeerKnown>, manual: &mut ManualPeers,
+        departed: &mut Vec<(u64, Instant)>, address: SocketAddr, instance: Option<u64>) -> bool {
+    let found = peers_known.iter().find(|r| r.address == address
+        || (instance.is_some() && r.instance == instance));
+    if found.is_some_and(|a| a.address.ip() != address.ip() || a.identity.is_some()) {
+        return true;
+    }
+    on_leave(to_app, links, peers_known, manual, departed, address, instance)
+}
+
+/// Tells the peers that understand it that this one is leaving, then closes the connections.
+async fn say_goodbye(links: &Links) {
+    let bytes = seal(links.identity.fingerprint, Kind::Control(Control::Leave));
+    let _ = timeout(GOODBYE_TIME, async {
+        for link in links.list.iter().filter(|r| r.understood) {
+            let _ = link.connection.send(bytes.clone().into()).await;
+        }
+    }).await;
+    for link in &links.list {
+        link.connection.close(Some("leaving".into()));
+    }
+}
+
+/// Splits `host:port` or `host` into its parts, using the default port for the latter.
+fn split_host(spec: &str) -> (String, u16) {
+    if let Ok(address) = spec.parse::<SocketAddr>() {
+        return (address.ip().to_string(), address.port());
+    }
+    if let Ok(ip) = spec.parse::<IpAddr>() {
+        return (ip.to_string(), DEFAULT_PORT);
+    }
+    if let Some((host, port)) = spec.rsplit_once(':') {
+        if let Ok(port) = port.parse() {
+            return (host.to_string(), port);
+        }
+    }
+    (spec.to_string(), DEFAULT_PORT)
+}
+
+async fn task_reach(nodes: Nodes, spec: String, to_p2p: TSender<Reached>) {
+    let result = reach(&nodes, &spec).await;
+    let _ = to_p2p.send((spec, result)).await;
+}
+
+async fn reach(nodes: &Nodes, spec: &str)
+        -> Result<(SocketAddr, Connection, ConnectionIncoming), String> {
+    let (host, port) = split_host(spec);
+    let addresses = lookup_host((host.as_str(), port)).await
+        .map_err(|a| a.to_string())?;
+
+    let mut result = Err("host not found".to_string());
+    for address in addresses {
+        let node = match nodes.for_address(&address) {
+            Some(a) => a,
+            None => continue,
+        };
+        match node.connect_to(&address).await {
+            Ok((connection, incoming_messages)) => {
+                return Ok((address, connection, incoming_messages));
+            }
+            Err(error) => result = Err(error.to_string()),
+        }
+    }
+    result
+}
+
+/// false = disconnected
+#[must_use]
+fn on_new_peer(to_app: &mut Sender<FromNet>, peers_known: &mut Vec<PeerKnown>,
+        address: SocketAddr, name: String, origin: Origin) -> bool {
+    let old = peers_known.iter().position(|r| r.address == address)
+        .map(|index| peers_known.remove(index));
+    let identity = old.as_ref().and_then(|a| a.identity);
+    peers_known.push(PeerKnown {
+        name: name.clone(),
+        address,
+        last_seen: Instant::now(),
+        found: old.as_ref().map(|a| a.found).unwrap_or_else(Instant::now),
+        origin,
+        instance: None,
+        claimed: old.as_ref().and_then(|a| a.claimed),
+        identity,
+        dialed: old.as_ref().is_some_and(|a| a.dialed),
+        hostname: old.as_ref().and_then(|a| a.hostname.clone()),
+        rooms: old.as_ref().map(|a| a.rooms.clone()).unwrap_or_default(),
+        presence: old.as_ref().and_then(|a| a.presence),
+        note: old.map(|a| a.note).unwrap_or_default(),
+    });
+
+    if to_app.send(peer_message(peers_known.last().unwrap())).is_err() {
+        return false;
+    }
+    true
+}
+
+/// false = disconnected
+#[must_use]
+fn on_peer(to_app: &mut Sender<FromNet>, peers_known: &mut Vec<PeerKnown>, rejected: &Rejected,
+        address: SocketAddr, ping: Ping) -> bool {
+    // A peer with both IPv4 and IPv6 pings on both. It's kept under its IPv4 address, the same as
+    // it keeps this computer, so that it only shows up once and connections go over IPv4 both
+    // ways. Otherwise whichever address was heard first is kept.
+    let twin = peers_known.iter().position(|r| r.address != address
+        && ping.instance.is_some() && r.instance == ping.instance);
+    if let Some(index) = twin {
+        let forgotten = if address.is_ipv4() && peers_known[index].address.is_ipv6() {
+            // Its identity has to be proven again over the new address.
+            Some(peers_known.remove(index).address)
+        } else {
+            // Made when it connected over this address before its pings were heard here.
+            let incoming = peers_known.iter()
+                .position(|r| r.address == address && r.origin == Origin::Incoming);
+            incoming.map(|index| peers_known.remove(index).address)
+        };
+        if let Some(forgotten) = forgotten {
+            if to_app.send(FromNet::Peerbgone(forgotten)).is_err() {
+                return false;
+            }
+        }
+    }
+    let found = peers_known.iter().position(|r| r.address == address
+        || (ping.instance.is_some() && r.instance == ping.instance));
+
+    let peer = if let Some(index) = found {
         let peer = &mut peers_known[index];
         peer.name.clear();
-        peer.name.push_str(&name);
+        peer.name.push_str(&ping.name);
         peer.last_seen = Instant::now();
+        peer.instance = ping.instance;
+        peer.claimed = ping.fingerprint;
+        peer.hostname = ping.hostname;
+        peer.rooms = ping.rooms;
+        peer.presence = ping.presence;
+        peer.note = ping.note;
+        if peer.origin == Origin::Incoming {
+            peer.origin = Origin::Discovered;
+        }
+        peer
+    } else if peers_known.len() >= MAX_PEERS {
+        rejected.count(&rejected.too_many, address.ip());
+        return true;
     } else {
         peers_known.push(PeerKnown {
-            name,
+            name: ping.name,
             address,
             last_seen: Instant::now(),
+            found: Instant::now(),
+            origin: Origin::Discovered,
+            instance: ping.instance,
+            claimed: ping.fingerprint,
+            identity: None,
+            dialed: false,
+            hostname: ping.hostname,
+            rooms: ping.rooms,
+            presence: ping.presence,
+            note: ping.note,
         });
+        peers_known.last().unwrap()
+    };
+
+    // Shown by `on_control` once it proves the key, or by a later ping if it doesn't.
+    if peer.claimed.is_some() && peer.identity.is_none() && peer.found.elapsed() < PROOF_WAIT {
+        return true;
+    }
+    if to_app.send(peer_message(peer)).is_err() {
+        return false;
+    }
+    true
+}
+
+fn peer_message(peer: &PeerKnown) -> FromNet {
+    FromNet::Peer {
+        name: peer.name.clone(),
+        address: peer.address,
+        identity: peer.identity.map(|a| to_hex(&a)),
+        hostname: peer.hostname.clone(),
+        rooms: peer.rooms.clone(),
+        presence: peer.presence,
+        note: peer.note.clone(),
     }
 }
 
-fn on_connection(to_app: Sender<FromNet>, to_log: TSender<ToLog>,
-        connections: &mut Vec<Connection>, peers: &[PeerKnown],
+fn on_connection(links: &mut Links, peers: &[PeerKnown],
         connection: Connection, incoming_messages: ConnectionIncoming) {
-    let ip = connection.remote_address().ip();
+    let address = connection.remote_address();
+    if links.gate.blocks(peers, address) {
+        connection.close(None);
+        return;
+    }
 
-    if let Some(index) = connections
-            .iter().position(|r| r.remote_address().ip() == ip) {
-        if connection.remote_address() != connections[index].remote_address() {
-            connections[index].close(Some("connected on new port".into()));
-        }
-        connections[index] = connection;
+    let found = peers.iter().find(|r| r.address == address);
+    let understood = found.is_some_and(|a| a.claimed.is_some());
+    let id = connection.id();
+    let link = Link {connection, challenge: None, understood};
+    if let Some(index) = links.list
+            .iter().position(|r| r.connection.remote_address() == address) {
+        links.list[index] = link;
     } else {
-        connections.push(connection);
+        links.list.push(link);
     }
     
-    let found = peers.iter().find(|r| r.address.ip() == ip);
-    let name = found.map(|a| a.name.clone()).unwrap_or(ip.to_string());
+    // Peers whose pings have no fingerprint are too old to know what to do with a hello and
+    // would show it as text. Ones that haven't pinged yet are greeted anyway so that the
+    // handshake doesn't depend on which gets there first.
+    if !found.is_some_and(|a| a.origin == Origin::Discovered && a.claimed.is_none()) {
+        links.greet(address);
+    }
+
+    spawn(task_receive_one(links.to_p2p.clone(), address, id, incoming_messages));
+}
+
+/// Starts the handshake with peers that have a fingerprint but haven't proven it yet, connecting
+/// to them first if needed so that their identity is known before any messages are sent.
+fn check_identities(nodes: &Nodes, links: &mut Links, peers_known: &mut [PeerKnown],
+        to_p2p: &TSender<Dialed>) {
+    for peer in peers_known.iter_mut() {
+        if peer.claimed.is_none() || peer.identity.is_some() {
+            continue;
+        }
+        if links.gate.blocks_peer(peer.address, peer.claimed) {
+            continue;
+        }
+        if links.find(peer.address).is_some() {
+            links.understood(peer.address);
+        } else if !peer.dialed {
+            if let Some(node) = nodes.for_address(&peer.address) {
+                peer.dialed = true;
+                spawn(task_dial(node.clone(), peer.address, to_p2p.clone()));
+            }
+        }
+    }
+}
+
+async fn task_dial(node: Endpoint, address: SocketAddr, to_p2p: TSender<Dialed>) {
+    let result = node.connect_to(&address).await
+        .map_err(|a| a.to_string());
+    let _ = to_p2p.send(result).await;
+}
+
+/// Envelopes claiming to be from a different key than the one the peer proved it has are dropped.
+/// The ones from before the proof are let through since that's how the handshake gets done.
+fn sent_by(peers: &[PeerKnown], address: SocketAddr, envelope: &Envelope) -> bool {
+    let found = peers.iter().find(|r| r.address == address);
+    match found.and_then(|a| a.identity) {
+        Some(identity) => identity == envelope.sender,
+        None => true,
+    }
+}
+
+/// Doesn't wait for it to be sent. If the connection is gone then the next message to the peer
+/// will find out.
+pub fn send_control(connection: Connection, sender: Fingerprint, control: Control) {
+    send_kind(connection, sender, Kind::Control(control));
+}
+
+/// Doesn't wait for it to be sent, like `send_control`.
+fn send_kind(connection: Connection, sender: Fingerprint, kind: Kind) {
+    let bytes = seal(sender, kind);
+    spawn(async move {
+        let _ = connection.send(bytes.into()).await;
+    });
+}
+
+fn is_proven(peers: &[PeerKnown], address: SocketAddr) -> bool {
+    peers.iter().any(|r| r.address == address && r.identity.is_some())
+}
+
+/// Passes a group on to the UI, which checks that the sender is in it. Left out for peers whose
+/// messages wouldn't get through and ones that haven't proven who they are.
+/// false = disconnected
+#[must_use]
+fn on_group(to_app: &mut Sender<FromNet>, gate: &Gate, peers: &[PeerKnown], source: SocketAddr,
+        group: Group) -> bool {
+    if gate.blocks(peers, source) || !gate.allows(peers, source) || !is_proven(peers, source) {
+        return true;
+    }
+    if to_app.send(FromNet::GroupShared {source, group}).is_err() {
+        return false;
+    }
+    true
+}
 
-    spawn(task_receive_one(to_app, to_log, ip, name, incoming_messages));
+/// Changes a received message. One that's held is changed before the user sees it, or dropped if
+/// it was retracted.
+/// false = disconnected
+#[must_use]
+async fn on_edit(to_app: &mut Sender<FromNet>, to_log: &mut TSender<ToLog>, gate: &mut Gate,
+        peers: &[PeerKnown], source: SocketAddr, id: u64, content: Option<String>) -> bool {
+    if gate.blocks(peers, source) {
+        return true;
+    }
+    let found = gate.held.iter().position(|r| r.0 == source && r.1.receipt == Some(id));
+    if let Some(index) = found {
+        match content {
+            Some(content) => gate.held[index].1.content = content,
+            None => {
+                gate.held.remove(index);
+                let count = gate.held.iter().filter(|r| r.0 == source).count();
+                if to_app.send(FromNet::Request {address: source, count}).is_err() {
+                    return false;
+                }
+            }
+        }
+        return true;
+    }
+    if !gate.allows(peers, source) {
+        return true;
+    }
+
+    let found = peers.iter().find(|r| r.address == source);
+    let name = found.map(|a| a.name.clone()).unwrap_or(source.to_string());
+    let entry = match &content {
+        Some(content) => format!("\nedited message from [{}] [{}] {}", name, source, content),
+        None => format!("\nretracted message from [{}] [{}]", name, source),
+    };
+    if to_log.send(ToLog::LogMessage(entry)).await.is_err() {
+        return false;
+    }
+    if to_app.send(FromNet::Edited {source, id, content}).is_err() {
+        return false;
+    }
+    true
+}
+
+/// Left out for peers whose messages wouldn't get through, like the messages themselves.
+/// false = disconnected
+#[must_use]
+fn on_typing(to_app: &mut Sender<FromNet>, gate: &Gate, peers: &[PeerKnown],
+        source: SocketAddr) -> bool {
+    if gate.blocks(peers, source) || !gate.allows(peers, source) {
+        return true;
+    }
+    if to_app.send(FromNet::Typing(source)).is_err() {
+        return false;
+    }
+    true
+}
+
+/// Passes a receipt for a sent message on to the UI. Peers can only send them for messages that
+/// were sent to them.
+/// false = disconnected
+#[must_use]
+fn on_receipt(to_app: &mut Sender<FromNet>, links: &mut Links, address: SocketAddr, id: u64,
+        read: bool) -> bool {
+    let found = links.awaiting.iter().position(|r| r.0 == address && r.1 == id);
+    let index = if let Some(a) = found {
+        a
+    } else {
+        return true;
+    };
+    let message_id = links.awaiting[index].2;
+    let update = if read {
+        // Nothing comes after it.
+        links.awaiting.remove(index);
+        FromNet::SendRead(message_id)
+    } else {
+        FromNet::SendDelivered(message_id)
+    };
+    if to_app.send(update).is_err() {
+        return false;
+    }
+    true
+}
+
+/// false = disconnected
+#[must_use]
+fn on_control(to_app: &mut Sender<FromNet>, links: &mut Links, peers_known: &mut [PeerKnown],
+        address: SocketAddr, control: Control) -> bool {
+    let found = links.list.iter().find(|r| r.connection.remote_address() == address);
+    let link = if let Some(a) = found {
+        a
+    } else {
+        return true;
+    };
+
+    match control {
+        Control::Hello {challenge, seen_at} => {
+            let proof = Control::Proof {
+                public_key: links.identity.public_key().to_vec(),
+                signature: links.identity.prove(&challenge, address, seen_at),
+                seen_at: address,
+            };
+            send_control(link.connection.clone(), links.identity.fingerprint, proof);
+            // The peer understands the handshake even if its pings haven't said so yet.
+            links.greet(address);
+        }
+        Control::Proof {public_key, signature, seen_at} => {
+            let challenge = if let Some(a) = link.challenge {
+                a
+            } else {
+                return true;
+            };
+            // A proof for a different connection names the far end of that one instead.
+            if !links.is_own(seen_at)
+                    || !check_proof(&public_key, &challenge, seen_at, address, &signature) {
+                return show_status(to_app, format!("{} failed to prove its identity", address));
+            }
+
+            let identity = fingerprint(&public_key);
+            let found = peers_known.iter_mut().find(|r| r.address == address);
+            if let Some(peer) = found {
+                if peer.identity != Some(identity) {
+                    peer.identity = Some(identity);
+
+                    if to_app.send(peer_message(peer)).is_err() {
+                        return false;
+                    }
+                }
+            }
+            links.cut_blocked(peers_known);
+        }
+        Control::Leave | Control::File(_) => {} // handled by on_leave and on_file
+    }
+    true
+}
+
+/// Passes file controls on to the transfers, leaving out blocked peers and declining offers from
+/// peers whose messages would be held.
+/// false = disconnected
+#[must_use]
+async fn on_file(to_app: &mut Sender<FromNet>, links: &Links, peers: &[PeerKnown],
+        files: &mut Transfers, address: SocketAddr, control: FileControl) -> bool {
+    let connection = match links.find(address) {
+        Some(a) if !links.gate.blocks(peers, address) => a.clone(),
+        _ => return true,
+    };
+    if let FileControl::Offer {id, ..} = control {
+        if !links.gate.allows(peers, address) {
+            let cancel = Control::File(FileControl::Cancel {id});
+            send_control(connection, links.identity.fingerprint, cancel);
+            return true;
+        }
+    }
+    files.receive(to_app, connection, control).await
 }
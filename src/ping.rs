@@ -0,0 +1,365 @@
+//! Format of the discovery packets sent by `network_broadcast`.
+//!
+//! ```text
+//! len | name (len bytes) | 0 | port (2 bytes) | MAGIC | version | field*
+//! ```
+//!
+//! The first part is the whole packet as sent by builds from before the format was versioned.
+//! Those builds ignore anything after the port, so it stays at the front to keep them working.
+//! Everything new goes into the fields after it, each one written as `type | len (2 bytes) | value`.
+//! Fields with an unrecognized type are skipped so that newer builds can add fields without
+//! breaking older ones. All numbers are big-endian.
+
+use std::str::from_utf8;
+use crate::identity::Fingerprint;
+
+const MAGIC: &[u8] = b"SCLAN";
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Field types
+const INSTANCE: u8 = 1;
+const FINGERPRINT: u8 = 2;
+const LEAVING: u8 = 3;
+const ROOMS: u8 = 4;
+const PRESENCE: u8 = 5;
+const HOSTNAME: u8 = 6;
+
+/// Most bytes of room names that fit in a ping.
+const MAX_ROOMS_LEN: usize = 512;
+/// Most rooms to be in at once. Along with `MAX_ROOM_NAME_LEN` this keeps them well inside of
+/// `MAX_ROOMS_LEN` and the 255 bytes of an mDNS property.
+pub const MAX_ROOMS: usize = 8;
+pub const MAX_ROOM_NAME_LEN: usize = 30;
+/// Most bytes of the note that goes with the presence.
+pub const MAX_NOTE_LEN: usize = 100;
+
+#[derive(Clone, Default)]
+pub struct Ping {
+    /// The one the user picked, or the host name if they didn't.
+    pub name: String,
+    /// Only sent when it's different from the name.
+    pub hostname: Option<String>,
+    /// Where the sender receives messages.
+    pub port: u16,
+    /// 0 if the sender is from before the versioned format.
+    pub version: u8,
+    /// Random number picked when the sender started, to tell apart several copies of sclan
+    /// running on the same machine.
+    pub instance: Option<u64>,
+    /// Of the key that the sender will prove it has when connected to.
+    pub fingerprint: Option<Fingerprint>,
+    /// The sender is closing. Sent once in place of the usual ping so that it's forgotten right
+    /// away instead of when its pings stop.
+    pub leaving: bool,
+    /// Only peers in one of the same rooms show up. Empty for the ones that haven't joined any.
+    pub rooms: Vec<String>,
+    /// None for builds from before presence existed.
+    pub presence: Option<Presence>,
+    /// Written by the user to go along with the presence. Can be empty.
+    pub note: String,
+}
+
+/// Whether the user is around to answer messages.
+#[derive(Copy, Clone, PartialEq, Eq, Default)]
+pub enum Presence {
+    #[default]
+    Available,
+    Away,
+    /// Do not disturb
+    Busy,
+}
+
+impl Presence {
+    pub fn name(self) -> &'static str {
+        match self {
+            Presence::Available => "available",
+            Presence::Away => "away",
+            Presence::Busy => "busy",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Presence> {
+        match name {
+            "available" => Some(Presence::Available),
+            "away" => Some(Presence::Away),
+            "busy" => Some(Presence::Busy),
+            _ => None,
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            Presence::Available => 0,
+            Presence::Away => 1,
+            Presence::Busy => 2,
+        }
+    }
+
+    /// Unknown ones are from newer builds and count as available.
+    fn from_byte(byte: u8) -> Presence {
+        match byte {
+            1 => Presence::Away,
+            2 => Presence::Busy,
+            _ => Presence::Available,
+        }
+    }
+}
+
+/// Whether a peer in `theirs` should be shown to one in `ours`. Peers that haven't joined any
+/// rooms only see each other, which includes builds from before rooms existed.
+pub fn shares_room(ours: &[String], theirs: &[String]) -> bool {
+    if ours.is_empty() {
+        return theirs.is_empty();
+    }
+    ours.iter().any(|a| theirs.contains(a))
+}
+
+pub fn encode_ping(ping: &Ping) -> Vec<u8> {
+    let name = truncate(&ping.name, u8::MAX as usize);
+    let mut message = vec![name.len() as u8];
+    message.extend_from_slice(name.as_bytes());
+
+    message.push(0);
+
+    message.extend_from_slice(&ping.port.to_be_bytes());
+
+    message.extend_from_slice(MAGIC);
+    message.push(PROTOCOL_VERSION);
+
+    if let Some(instance) = ping.instance {
+        push_field(&mut message, INSTANCE, &instance.to_be_bytes());
+    }
+    if let Some(fingerprint) = ping.fingerprint {
+        push_field(&mut message, FINGERPRINT, &fingerprint);
+    }
+    if ping.leaving {
+        push_field(&mut message, LEAVING, &[]);
+    }
+    if !ping.rooms.is_empty() {
+        // Room names can't have commas in them. Ones that don't fit are left out whole so that
+        // a cut off name doesn't put the peer in a different room.
+        let mut rooms = String::new();
+        for room in &ping.rooms {
+            let separator = usize::from(!rooms.is_empty());
+            if rooms.len() + separator + room.len() > MAX_ROOMS_LEN {
+                break;
+            }
+            if separator > 0 {
+                rooms.push(',');
+            }
+            rooms.push_str(room);
+        }
+        push_field(&mut message, ROOMS, rooms.as_bytes());
+    }
+    if let Some(hostname) = &ping.hostname {
+        push_field(&mut message, HOSTNAME, truncate(hostname, u8::MAX as usize).as_bytes());
+    }
+    if let Some(presence) = ping.presence {
+        let mut value = vec![presence.to_byte()];
+        value.extend_from_slice(truncate(&ping.note, MAX_NOTE_LEN).as_bytes());
+        push_field(&mut message, PRESENCE, &value);
+    }
+
+    message
+}
+
+pub fn parse_ping(message: &[u8]) -> Option<Ping> {
+    let len = *message.first()?;
+    let name_bytes = message.get(1..1 + len as usize)?;
+    let name = from_utf8(name_bytes).ok()?;
+
+    if message.get(1 + len as usize) != Some(&0) {
+        // use zero termination just to make it easier to catch malformed packets
+        return None;
+    }
+
+    let port_index = 2 + len as usize;
+    let port_bytes = message.get(port_index..port_index + 2)?;
+
+    let port = u16::from_be_bytes(port_bytes.try_into().unwrap());
+    if port == 0 {
+        return None;
+    }
+
+    let mut ping = Ping {
+        name: strip_controls(name),
+        hostname: None,
+        port,
+        version: 0,
+        instance: None,
+        fingerprint: None,
+        leaving: false,
+        rooms: vec![],
+        presence: None,
+        note: String::new(),
+    };
+
+    let rest = &message[port_index + 2..];
+    if rest.is_empty() {
+        return Some(ping);
+    }
+    let rest = rest.strip_prefix(MAGIC)?;
+    let (&version, mut fields) = rest.split_first()?;
+    if version == 0 {
+        return None;
+    }
+    ping.version = version;
+
+    while !fields.is_empty() {
+        let (kind, value, after) = next_field(fields)?;
+        match kind {
+            INSTANCE => ping.instance = Some(u64::from_be_bytes(value.try_into().ok()?)),
+            FINGERPRINT => ping.fingerprint = Some(value.try_into().ok()?),
+            LEAVING => ping.leaving = true,
+            ROOMS => {
+                ping.rooms = from_utf8(value).ok()?.split(',')
+                    .map(strip_controls)
+                    .filter(|a| !a.is_empty())
+                    .collect();
+            }
+            HOSTNAME => ping.hostname = Some(strip_controls(from_utf8(value).ok()?)),
+            PRESENCE => {
+                let (&presence, note) = value.split_first()?;
+                ping.presence = Some(Presence::from_byte(presence));
+                ping.note = strip_controls(from_utf8(note).ok()?);
+            }
+            _ => {} // from a newer build
+        }
+        fields = after;
+    }
+
+    Some(ping)
+}
+
+/// Text from other computers ends up in files that are read a line at a time and on the screen,
+/// so line breaks and the like are taken out.
+pub fn strip_controls(text: &str) -> String {
+    text.chars().filter(|a| !a.is_control()).collect()
+}
+
+/// Returns (type, value, remaining bytes)
+fn next_field(bytes: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let kind = *bytes.first()?;
+    let len = u16::from_be_bytes(bytes.get(1..3)?.try_into().unwrap()) as usize;
+    let value = bytes.get(3..3 + len)?;
+    Some((kind, value, &bytes[3 + len..]))
+}
+
+fn push_field(message: &mut Vec<u8>, kind: u8, value: &[u8]) {
+    let len = value.len().min(u16::MAX as usize);
+    message.push(kind);
+    message.extend_from_slice(&(len as u16).to_be_bytes());
+    message.extend_from_slice(&value[..len]);
+}
+
+/// Cuts at a character boundary so that the result is still valid UTF-8.
+fn truncate(text: &str, max_len: usize) -> &str {
+    if text.len() <= max_len {
+        return text;
+    }
+    let mut end = max_len;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full_ping() -> Ping {
+        Ping {
+            name: "alice".into(),
+            hostname: Some("alice-laptop".into()),
+            port: 31332,
+            version: PROTOCOL_VERSION,
+            instance: Some(7),
+            fingerprint: Some([3; 32]),
+            leaving: false,
+            rooms: vec!["backend".into(), "lunch".into()],
+            presence: Some(Presence::Busy),
+            note: "in a meeting".into(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let ping = parse_ping(&encode_ping(&full_ping())).unwrap();
+
+        assert_eq!(ping.name, "alice");
+        assert_eq!(ping.hostname.as_deref(), Some("alice-laptop"));
+        assert_eq!(ping.port, 31332);
+        assert_eq!(ping.version, PROTOCOL_VERSION);
+        assert_eq!(ping.instance, Some(7));
+        assert_eq!(ping.fingerprint, Some([3; 32]));
+        assert!(!ping.leaving);
+        assert_eq!(ping.rooms, ["backend", "lunch"]);
+        assert!(matches!(ping.presence, Some(Presence::Busy)));
+        assert_eq!(ping.note, "in a meeting");
+    }
+
+    #[test]
+    fn legacy_packet() {
+        let mut message = vec![3];
+        message.extend_from_slice(b"bob\0");
+        message.extend_from_slice(&4000u16.to_be_bytes());
+
+        let ping = parse_ping(&message).unwrap();
+
+        assert_eq!(ping.name, "bob");
+        assert_eq!(ping.port, 4000);
+        assert_eq!(ping.version, 0);
+        assert!(ping.instance.is_none() && ping.fingerprint.is_none());
+    }
+
+    #[test]
+    fn truncated_packet() {
+        let message = encode_ping(&full_ping());
+        for len in 0..message.len() {
+            // Cutting between fields leaves a shorter packet that's still whole.
+            if let Some(ping) = parse_ping(&message[..len]) {
+                assert_eq!(ping.name, "alice");
+                assert_eq!(ping.port, 31332);
+            }
+        }
+        assert!(parse_ping(&message[..3]).is_none());
+        assert!(parse_ping(&message[..message.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn oversized_length() {
+        let mut message = vec![200];
+        message.extend_from_slice(b"bob\0");
+        message.extend_from_slice(&4000u16.to_be_bytes());
+        assert!(parse_ping(&message).is_none());
+
+        let mut message = encode_ping(&Ping {port: 4000, ..Default::default()});
+        message.push(HOSTNAME);
+        message.extend_from_slice(&u16::MAX.to_be_bytes());
+        message.extend_from_slice(b"host");
+        assert!(parse_ping(&message).is_none());
+    }
+
+    #[test]
+    fn unknown_field_is_skipped() {
+        let mut message = encode_ping(&Ping {port: 4000, ..Default::default()});
+        push_field(&mut message, 200, b"from the future");
+        push_field(&mut message, INSTANCE, &9u64.to_be_bytes());
+
+        let ping = parse_ping(&message).unwrap();
+
+        assert_eq!(ping.instance, Some(9));
+    }
+
+    #[test]
+    fn rooms_that_dont_fit_are_left_out_whole() {
+        let long = "x".repeat(MAX_ROOMS_LEN - 3);
+        let rooms = vec!["ab".into(), long.clone(), "cd".into()];
+        let ping = Ping {port: 4000, rooms, ..Default::default()};
+
+        let ping = parse_ping(&encode_ping(&ping)).unwrap();
+
+        assert_eq!(ping.rooms, ["ab".to_string(), long]);
+    }
+}
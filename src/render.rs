@@ -9,7 +9,9 @@ use tui::layout::{Alignment, Rect};
 use unicode_width::UnicodeWidthStr;
 use textwrap::wrap;
 use crate::App;
-use crate::data::{InputMode, MessageType, Message, LogState};
+use crate::data::{InputMode, MessageType, Message, LogState, Revision, display_name};
+use crate::ping::Presence;
+use crate::actions::peer_name;
 
 fn plain<'a, T>(message: T) -> Span<'a>
 where T: Into<Cow<'a, str>> {
@@ -70,7 +72,7 @@ pub fn ui_scrolling_list(area: Rect, title: &str, selection: &str, options: &[St
         }
     }
 
-    if options.len() == 0 {
+    if options.is_empty() {
         lines.push(Spans::from(faded(" (searching...) ")));
     }
 
@@ -86,15 +88,18 @@ pub fn ui_scrolling_list(area: Rect, title: &str, selection: &str, options: &[St
     Paragraph::new(lines)
 }
 
-pub fn ui_instructions(input_mode: InputMode, recipient_valid: bool,
+pub fn ui_instructions(input_mode: InputMode, recipient_chosen: bool,
                        text_entered: bool, output_displayed: bool,
-                       output_selected: bool, logging: LogState) -> Paragraph<'static> {
+                       output_selected: bool, sent_selected: bool,
+                       logging: LogState) -> Paragraph<'static> {
     let mut lines = vec![];
 
     lines.push(Spans::from("__________________"));
 
     if logging == LogState::Pending {
         lines.push(Spans::from("   Log starting..."));
+    } else if input_mode == InputMode::Normal && sent_selected {
+        lines.push(Spans::from(vec![bold("   [Del]"), plain("-retract")]));
     } else if logging == LogState::Active {
         lines.push(Spans::default());
     } else if input_mode == InputMode::Normal && logging == LogState::Inactive {
@@ -118,7 +123,9 @@ pub fn ui_instructions(input_mode: InputMode, recipient_valid: bool,
 
     lines.push(Spans::from(vec![bold("   [Tab]"), plain("-recipient")]));
     
-    if !recipient_valid {
+    if input_mode == InputMode::Command {
+        lines.push(Spans::from(vec![bold(" [Enter]"), plain("-run")]));
+    } else if !recipient_chosen {
         lines.push(Spans::default());
     } else if input_mode == InputMode::Normal {
         lines.push(Spans::from(vec![bold(" [Enter]"), plain("-write")]));
@@ -128,7 +135,9 @@ pub fn ui_instructions(input_mode: InputMode, recipient_valid: bool,
         lines.push(Spans::default());
     }
 
-    if !recipient_valid {
+    if input_mode == InputMode::Normal && sent_selected {
+        lines.push(Spans::from(vec![bold("     [E]"), plain("-edit")]));
+    } else if !recipient_chosen {
         lines.push(Spans::default());
     } else if input_mode == InputMode::Editing {
         lines.push(Spans::from(vec![bold("[Sh+Ent]"), plain("-new line")]));
@@ -149,9 +158,11 @@ pub fn ui_instructions(input_mode: InputMode, recipient_valid: bool,
     }
 
     if input_mode == InputMode::Normal {
+        lines.push(Spans::from(vec![bold("     [/]"), plain("-command")]));
         lines.push(Spans::from(vec![bold("     [Q]"), plain("-quit")]));
     } else {
         lines.push(Spans::default());
+        lines.push(Spans::default());
     }
 
     Paragraph::new(lines)
@@ -160,7 +171,16 @@ pub fn ui_instructions(input_mode: InputMode, recipient_valid: bool,
 pub fn render_input<B: Backend>(f: &mut Frame<B>, app: &App, cell_input: Rect) {
     let mut input_block = Block::default()
         .borders(Borders::ALL);
-    if app.recipient.peer.name.len() == 0 {
+    if app.input_mode == InputMode::Command {
+        input_block = input_block.title(" command - type help for a list ");
+    } else if app.editing.is_some() {
+        input_block = input_block.title(" editing a sent message ");
+    } else if app.recipient.everyone {
+        input_block = input_block.title(" sending to: everyone ");
+    } else if let Some(id) = app.recipient.group {
+        let name = app.groups.iter().find(|a| a.id == id).map(|a| a.name.as_str()).unwrap_or("");
+        input_block = input_block.title(format!(" sending to: [{}] ", name));
+    } else if app.recipient.peer.name.is_empty() {
         input_block = input_block.title(" Select a recipient. ");
     } else {
         let address = if app.recipient.valid {
@@ -173,7 +193,13 @@ pub fn render_input<B: Backend>(f: &mut Frame<B>, app: &App, cell_input: Rect) {
         input_block = input_block.title(send_to);
     }
 
-    let line = app.input.split('\n').last().unwrap_or("");
+    let command;
+    let line = if app.input_mode == InputMode::Command {
+        command = format!("/{}", app.command);
+        &command
+    } else {
+        app.input.split('\n').last().unwrap_or("")
+    };
     let start = line.len().saturating_sub(cell_input.width as usize - 3);
     let end = min(line.len(), start + cell_input.width as usize - 3);
     let line = line.get(start..end).unwrap_or("<range error>");
@@ -181,7 +207,7 @@ pub fn render_input<B: Backend>(f: &mut Frame<B>, app: &App, cell_input: Rect) {
     let input = Paragraph::new(line)
         .style(match app.input_mode {
             InputMode::Normal => Style::default(),
-            InputMode::Editing => Style::default().fg(Color::Yellow),
+            InputMode::Editing | InputMode::Command => Style::default().fg(Color::Yellow),
         })
         .block(input_block);
 
@@ -189,7 +215,7 @@ pub fn render_input<B: Backend>(f: &mut Frame<B>, app: &App, cell_input: Rect) {
     match app.input_mode {
         InputMode::Normal => {}
 
-        InputMode::Editing => {
+        InputMode::Editing | InputMode::Command => {
             // Make the cursor visible and ask tui-rs to put it at the specified
             // coordinates after rendering
             f.set_cursor(
@@ -202,8 +228,30 @@ pub fn render_input<B: Backend>(f: &mut Frame<B>, app: &App, cell_input: Rect) {
     }
 }
 
+/// Upper limit on how many local addresses to list so that the peer list doesn't get squeezed out.
+pub const MAX_ADDRESSES_SHOWN: usize = 4;
+
+/// Nothing for available unless there's a note.
+pub fn presence_label(presence: Option<Presence>, note: &str) -> String {
+    match (presence, !note.is_empty()) {
+        (None, _) | (Some(Presence::Available), false) => String::new(),
+        (Some(Presence::Available), true) => format!(" ({})", note),
+        (Some(presence), false) => format!(" ({})", presence.name()),
+        (Some(presence), true) => format!(" ({}: {})", presence.name(), note),
+    }
+}
+
+/// Number of lines that `ui_info` needs.
+pub fn info_height(app: &App) -> u16 {
+    let addresses = app.lan.local_addrs.len().clamp(1, MAX_ADDRESSES_SHOWN + 1);
+    let requests = if app.requests.is_empty() { 0 } else { 1 };
+    let rooms = if app.config.rooms.is_empty() { 0 } else { 2 };
+    let presence = if presence_label(Some(app.presence), &app.note).is_empty() { 0 } else { 1 };
+    6 + addresses as u16 + requests + rooms + presence
+}
+
 pub fn ui_info<'a>(app: &'a App) -> Paragraph<'a> {
-    Paragraph::new(vec![
+    let mut lines = vec![
         Spans::from(vec![
             bold("SCLAN "),
             plain(env!("CARGO_PKG_VERSION")),
@@ -211,19 +259,49 @@ pub fn ui_info<'a>(app: &'a App) -> Paragraph<'a> {
         Spans::default(),
 
         Spans::from(faded("computer name:")),
-        if app.lan.local_name.len() > 0 {
-            Spans::from(bold(&app.lan.local_name))
+        if !display_name(app).is_empty() {
+            Spans::from(bold(display_name(app)))
         } else {
             Spans::from(faded("(pending...)"))
         },
+    ];
 
-        Spans::from(faded("internal address:")),
-        if app.lan.local_addr.len() > 0 {
-            Spans::from(bold(&app.lan.local_addr))
-        } else {
-            Spans::from(faded("(connecting...)"))
-        },
-    ])
+    let presence = presence_label(Some(app.presence), &app.note);
+    if !presence.is_empty() {
+        lines.push(Spans::from(faded(presence.trim().to_string())));
+    }
+
+    if app.lan.local_addrs.len() > 1 {
+        lines.push(Spans::from(faded("internal addresses:")));
+    } else {
+        lines.push(Spans::from(faded("internal address:")));
+    }
+    if app.lan.local_addrs.is_empty() {
+        lines.push(Spans::from(faded("(connecting...)")));
+    }
+    for address in app.lan.local_addrs.iter().take(MAX_ADDRESSES_SHOWN) {
+        lines.push(Spans::from(bold(address.to_string())));
+    }
+    if let Some(count) = app.lan.local_addrs.len().checked_sub(MAX_ADDRESSES_SHOWN) {
+        if count > 0 {
+            lines.push(Spans::from(format!(" ({} more) …", count)));
+        }
+    }
+
+    if !app.config.rooms.is_empty() {
+        lines.push(Spans::from(faded("rooms:")));
+        let rooms = app.config.rooms.iter().map(|a| format!("#{}", a)).collect::<Vec<_>>();
+        lines.push(Spans::from(bold(rooms.join(" "))));
+    }
+
+    if !app.requests.is_empty() {
+        lines.push(Spans::from(vec![
+            faded("requests: "),
+            Span::styled(app.requests.len().to_string(), Style::default().fg(Color::Yellow)),
+        ]));
+    }
+
+    Paragraph::new(lines)
 }
 
 fn message_heading(message: &Message) -> Spans<'static> {
@@ -233,10 +311,22 @@ fn message_heading(message: &Message) -> Spans<'static> {
             heading.push(bold("→"));
             heading.push(plain(" to    "));
         }
+        MessageType::Delivered => {
+            heading.push(bold("→ ✓"));
+            heading.push(plain(" to  "));
+        }
+        MessageType::Read => {
+            heading.push(bold("→ ✓✓"));
+            heading.push(plain(" to "));
+        }
         MessageType::Sending => {
             heading.push(bold("→"));
             heading.push(plain(" ...   "));
         }
+        MessageType::Queued => {
+            heading.push(bold("→"));
+            heading.push(plain(" queued"));
+        }
         MessageType::SendFailed => {
             heading.push(bold("→ x     "));
         }
@@ -261,10 +351,18 @@ fn message_heading(message: &Message) -> Spans<'static> {
         heading.push(plain(format!("{: <len$} ", "", len=len)));
     }
     heading.push(plain(message.timestamp.clone()));
+    match message.revision {
+        Revision::Original => {}
+        Revision::Edited => heading.push(plain(" (edited)")),
+        Revision::Retracted => heading.push(plain(" (retracted)")),
+    }
 
     let heading_color = match message.direction {
         MessageType::Sent => Color::Yellow,
+        MessageType::Delivered => Color::Yellow,
+        MessageType::Read => Color::LightGreen,
         MessageType::Sending => Color::DarkGray,
+        MessageType::Queued => Color::Magenta,
         MessageType::SendFailed => Color::Red,
         MessageType::Received => Color::LightCyan,
         MessageType::Error => Color::Red,
@@ -293,7 +391,7 @@ pub fn ui_messages(app: &App, area: Rect) -> Paragraph<'static> {
             focus_y = Some(lines.len() as u16);
         }
 
-        lines.push(message_heading(message.clone()));
+        lines.push(message_heading(message));
         for r in wrap(&message.content, view_width as usize) {
             lines.push(Spans::from(Span::styled(r.into_owned(), body_style)));
         }
@@ -323,6 +421,16 @@ pub fn ui_messages(app: &App, area: Rect) -> Paragraph<'static> {
         .scroll((y, 0))
 }
 
+/// Shown before the status, which still gets the right side.
+fn typing_label(app: &App) -> String {
+    let names = app.typing.iter().map(|a| peer_name(app, a.0)).collect::<Vec<_>>();
+    match names.len() {
+        0 => String::new(),
+        1 => format!("{} is typing…  ", names[0]),
+        _ => format!("{} are typing…  ", names.join(", ")),
+    }
+}
+
 pub fn ui_status<'a>(app: &'a App) -> Paragraph<'a> {
     let style = if app.status.is_error {
         Style::default().fg(Color::Gray).bg(Color::Red)
@@ -330,6 +438,7 @@ pub fn ui_status<'a>(app: &'a App) -> Paragraph<'a> {
         Style::default()
     };
     Paragraph::new(Spans::from(vec![
+        faded(typing_label(app)),
         Span::styled(" ", style),
         Span::styled(&app.status.content, style),
         Span::styled(" ", style),